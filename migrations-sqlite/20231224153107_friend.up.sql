@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE friend (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR(255) NOT NULL,
+    dob DATE NOT NULL,
+    email VARCHAR(255) NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE friend ADD COLUMN locale VARCHAR(35) NOT NULL DEFAULT 'en';
+ALTER TABLE users ADD COLUMN locale VARCHAR(35) NOT NULL DEFAULT 'en';
@@ -0,0 +1,29 @@
+-- Add down migration script here
+-- SQLite cannot drop a column used by a foreign key, the table is rebuilt instead
+CREATE TABLE sent_otps (
+    otp VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    created_for VARCHAR(255) NOT NULL,
+    used BOOLEAN NOT NULL,
+    sent BOOLEAN NOT NULL
+);
+INSERT INTO sent_otps (otp, email, created_for, used, sent)
+SELECT otp, email, created_for, used, outbox_id IS NOT NULL FROM otps;
+DROP TABLE otps;
+ALTER TABLE sent_otps RENAME TO otps;
+
+CREATE TABLE friend_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    friend_id INTEGER NOT NULL REFERENCES friend (id) ON DELETE CASCADE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    deadline TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+INSERT INTO friend_outbox (id, friend_id, status, attempts, last_error, next_attempt_at, deadline, created_at)
+SELECT id, friend_id, status, attempts, last_error, next_attempt_at, deadline, created_at
+FROM outbox WHERE friend_id IS NOT NULL;
+DROP TABLE outbox;
+ALTER TABLE friend_outbox RENAME TO outbox;
@@ -0,0 +1,30 @@
+-- Add up migration script here
+-- Entries queued before the outbox stored whole messages cannot be delivered anymore
+UPDATE outbox SET status = 'failed', last_error = 'Dropped while moving to the message outbox'
+WHERE status = 'pending';
+
+-- SQLite cannot drop NOT NULL from a column, the table is rebuilt instead
+CREATE TABLE message_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    friend_id INTEGER REFERENCES friend (id) ON DELETE CASCADE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    deadline TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    kind VARCHAR(16) NOT NULL,
+    recipient VARCHAR(255) NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    dedupe_key VARCHAR(255) UNIQUE,
+    sent_at TEXT
+);
+INSERT INTO message_outbox (id, friend_id, status, attempts, last_error, next_attempt_at, deadline, created_at, kind, recipient, subject, body)
+SELECT id, friend_id, status, attempts, last_error, next_attempt_at, deadline, created_at, 'birthday', '', '', ''
+FROM outbox;
+DROP TABLE outbox;
+ALTER TABLE message_outbox RENAME TO outbox;
+
+ALTER TABLE otps ADD COLUMN outbox_id INTEGER REFERENCES outbox (id) ON DELETE SET NULL;
+ALTER TABLE otps DROP COLUMN sent;
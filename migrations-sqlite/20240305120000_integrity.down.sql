@@ -0,0 +1,22 @@
+-- Add down migration script here
+DROP INDEX friend_birthday_idx;
+
+CREATE TABLE unkeyed_otps (
+    otp VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    created_for VARCHAR(255) NOT NULL,
+    used BOOLEAN NOT NULL,
+    outbox_id INTEGER REFERENCES outbox (id) ON DELETE SET NULL
+);
+INSERT INTO unkeyed_otps (otp, email, created_for, used, outbox_id)
+SELECT otp, email, created_for, used, outbox_id FROM otps;
+DROP TABLE otps;
+ALTER TABLE unkeyed_otps RENAME TO otps;
+
+DROP INDEX users_email_key;
+DROP INDEX friend_email_key;
+-- The duplicates are put back
+INSERT INTO friend SELECT * FROM friend_duplicates;
+INSERT INTO users SELECT * FROM users_duplicates;
+DROP TABLE friend_duplicates;
+DROP TABLE users_duplicates;
@@ -0,0 +1,29 @@
+-- Add up migration script here
+-- Emails are unique regardless of case, older duplicates keep the first row
+-- The other rows are moved to friend_duplicates and users_duplicates to be reviewed, not lost
+CREATE TABLE friend_duplicates AS
+SELECT * FROM friend WHERE id NOT IN (SELECT MIN(id) FROM friend GROUP BY lower(email));
+CREATE TABLE users_duplicates AS
+SELECT * FROM users WHERE id NOT IN (SELECT MIN(id) FROM users GROUP BY lower(email));
+DELETE FROM friend WHERE id IN (SELECT id FROM friend_duplicates);
+DELETE FROM users WHERE id IN (SELECT id FROM users_duplicates);
+CREATE UNIQUE INDEX friend_email_key ON friend (lower(email));
+CREATE UNIQUE INDEX users_email_key ON users (lower(email));
+
+-- SQLite cannot add a primary key to a table, the table is rebuilt instead
+CREATE TABLE keyed_otps (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    otp VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    created_for VARCHAR(255) NOT NULL,
+    used BOOLEAN NOT NULL,
+    outbox_id INTEGER REFERENCES outbox (id) ON DELETE SET NULL
+);
+INSERT INTO keyed_otps (otp, email, created_for, used, outbox_id)
+SELECT otp, email, created_for, used, outbox_id FROM otps;
+DROP TABLE otps;
+ALTER TABLE keyed_otps RENAME TO otps;
+CREATE INDEX otps_email_idx ON otps (email);
+
+-- Supports the daily birthday lookup, the expressions match the query
+CREATE INDEX friend_birthday_idx ON friend (CAST(strftime('%m', dob) AS INTEGER), CAST(strftime('%d', dob) AS INTEGER));
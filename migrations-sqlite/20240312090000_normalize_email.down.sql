@@ -0,0 +1,4 @@
+-- Add down migration script here
+-- The original case of the emails is lost, only the index is restored
+DROP INDEX otps_email_idx;
+CREATE INDEX otps_email_idx ON otps (email);
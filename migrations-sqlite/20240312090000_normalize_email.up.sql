@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- Emails are stored trimmed and lowercased, addresses that only differed by case or spaces keep the first row
+-- The other rows join the duplicates kept aside by the integrity migration
+INSERT INTO friend_duplicates
+SELECT * FROM friend WHERE id NOT IN (SELECT MIN(id) FROM friend GROUP BY lower(trim(email)));
+INSERT INTO users_duplicates
+SELECT * FROM users WHERE id NOT IN (SELECT MIN(id) FROM users GROUP BY lower(trim(email)));
+DELETE FROM friend WHERE id IN (SELECT id FROM friend_duplicates);
+DELETE FROM users WHERE id IN (SELECT id FROM users_duplicates);
+UPDATE friend SET email = lower(trim(email));
+UPDATE users SET email = lower(trim(email));
+UPDATE otps SET email = lower(trim(email));
+
+DROP INDEX otps_email_idx;
+CREATE INDEX otps_email_idx ON otps (lower(email));
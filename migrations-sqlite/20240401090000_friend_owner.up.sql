@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- Friends added by a logged in user belong to them and are removed with their account
+-- Friends added from the CLI or without a session have no owner
+CREATE TABLE friend_owner (
+    friend_id INTEGER PRIMARY KEY REFERENCES friend (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX friend_owner_user_id_idx ON friend_owner (user_id);
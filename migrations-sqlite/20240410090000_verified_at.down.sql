@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX users_unverified_idx;
+ALTER TABLE users DROP COLUMN verified_at;
+ALTER TABLE users DROP COLUMN created_at;
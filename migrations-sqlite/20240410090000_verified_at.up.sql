@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- An account is verified once the OTP sent at signup is entered, accounts that never are get removed
+-- The accounts created before could already log in, they count as verified
+-- SQLite only adds columns with a constant default, new accounts are given their creation time on insert
+ALTER TABLE users ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00Z';
+ALTER TABLE users ADD COLUMN verified_at TEXT;
+UPDATE users SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now');
+UPDATE users SET verified_at = created_at;
+CREATE INDEX users_unverified_idx ON users (created_at) WHERE verified_at IS NULL;
@@ -0,0 +1,12 @@
+-- Add down migration script here
+-- Fails while two owners have the same friend, one of them has to be removed first
+DROP INDEX friend_owner_email_key;
+CREATE UNIQUE INDEX friend_email_key ON friend (lower(email));
+CREATE TABLE friend_owner (
+    friend_id INTEGER PRIMARY KEY REFERENCES friend (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX friend_owner_user_id_idx ON friend_owner (user_id);
+INSERT INTO friend_owner (friend_id, user_id) SELECT id, owner_id FROM friend WHERE owner_id IS NOT NULL;
+DROP INDEX friend_owner_id_idx;
+ALTER TABLE friend DROP COLUMN owner_id;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- A friend's email is unique per owner, two users may both have the same friend
+-- The owner moves onto the friend row so one index can cover both
+ALTER TABLE friend ADD COLUMN owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+UPDATE friend SET owner_id = (SELECT user_id FROM friend_owner WHERE friend_id = friend.id);
+DROP TABLE friend_owner;
+CREATE INDEX friend_owner_id_idx ON friend (owner_id);
+-- Friends without an owner (added from the CLI) are unique among themselves
+DROP INDEX friend_email_key;
+CREATE UNIQUE INDEX friend_owner_email_key ON friend (COALESCE(owner_id, 0), lower(email));
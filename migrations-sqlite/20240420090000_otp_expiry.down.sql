@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE otps DROP COLUMN attempts;
+ALTER TABLE otps DROP COLUMN expires_at;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- An OTP is refused once it expires or after too many wrong tries, the OTPs sent before have expired
+-- SQLite only adds columns with a constant default, new OTPs are given their expiry on insert
+ALTER TABLE otps ADD COLUMN expires_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00Z';
+ALTER TABLE otps ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE outbox (
+    id SERIAL PRIMARY KEY,
+    friend_id INTEGER NOT NULL REFERENCES friend (id) ON DELETE CASCADE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deadline TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
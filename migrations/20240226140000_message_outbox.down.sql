@@ -0,0 +1,14 @@
+-- Add down migration script here
+ALTER TABLE otps ADD COLUMN sent BOOLEAN NOT NULL DEFAULT false;
+UPDATE otps SET sent = true WHERE outbox_id IS NOT NULL;
+ALTER TABLE otps ALTER COLUMN sent DROP DEFAULT;
+ALTER TABLE otps DROP COLUMN outbox_id;
+
+DELETE FROM outbox WHERE friend_id IS NULL;
+ALTER TABLE outbox DROP COLUMN sent_at;
+ALTER TABLE outbox DROP COLUMN dedupe_key;
+ALTER TABLE outbox DROP COLUMN body;
+ALTER TABLE outbox DROP COLUMN subject;
+ALTER TABLE outbox DROP COLUMN recipient;
+ALTER TABLE outbox DROP COLUMN kind;
+ALTER TABLE outbox ALTER COLUMN friend_id SET NOT NULL;
@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- Entries queued before the outbox stored whole messages cannot be delivered anymore
+UPDATE outbox SET status = 'failed', last_error = 'Dropped while moving to the message outbox'
+WHERE status = 'pending';
+
+ALTER TABLE outbox ALTER COLUMN friend_id DROP NOT NULL;
+ALTER TABLE outbox ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'birthday';
+ALTER TABLE outbox ADD COLUMN recipient VARCHAR(255) NOT NULL DEFAULT '';
+ALTER TABLE outbox ADD COLUMN subject TEXT NOT NULL DEFAULT '';
+ALTER TABLE outbox ADD COLUMN body TEXT NOT NULL DEFAULT '';
+ALTER TABLE outbox ADD COLUMN dedupe_key VARCHAR(255) UNIQUE;
+ALTER TABLE outbox ADD COLUMN sent_at TIMESTAMPTZ;
+ALTER TABLE outbox ALTER COLUMN kind DROP DEFAULT;
+ALTER TABLE outbox ALTER COLUMN recipient DROP DEFAULT;
+ALTER TABLE outbox ALTER COLUMN subject DROP DEFAULT;
+ALTER TABLE outbox ALTER COLUMN body DROP DEFAULT;
+
+ALTER TABLE otps ADD COLUMN outbox_id INTEGER REFERENCES outbox (id) ON DELETE SET NULL;
+ALTER TABLE otps DROP COLUMN sent;
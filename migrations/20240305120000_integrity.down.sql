@@ -0,0 +1,11 @@
+-- Add down migration script here
+DROP INDEX friend_birthday_idx;
+DROP INDEX otps_email_idx;
+ALTER TABLE otps DROP COLUMN id;
+DROP INDEX users_email_key;
+DROP INDEX friend_email_key;
+-- The duplicates are put back
+INSERT INTO friend SELECT * FROM friend_duplicates;
+INSERT INTO users SELECT * FROM users_duplicates;
+DROP TABLE friend_duplicates;
+DROP TABLE users_duplicates;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- Emails are unique regardless of case, older duplicates keep the first row
+-- The other rows are moved to friend_duplicates and users_duplicates to be reviewed, not lost
+CREATE TABLE friend_duplicates AS
+SELECT * FROM friend WHERE id NOT IN (SELECT MIN(id) FROM friend GROUP BY lower(email));
+CREATE TABLE users_duplicates AS
+SELECT * FROM users WHERE id NOT IN (SELECT MIN(id) FROM users GROUP BY lower(email));
+DELETE FROM friend WHERE id IN (SELECT id FROM friend_duplicates);
+DELETE FROM users WHERE id IN (SELECT id FROM users_duplicates);
+CREATE UNIQUE INDEX friend_email_key ON friend (lower(email));
+CREATE UNIQUE INDEX users_email_key ON users (lower(email));
+
+ALTER TABLE otps ADD COLUMN id SERIAL PRIMARY KEY;
+CREATE INDEX otps_email_idx ON otps (email);
+
+-- Supports the daily birthday lookup, the expressions match the query
+CREATE INDEX friend_birthday_idx ON friend ((EXTRACT(MONTH FROM dob)::INTEGER), (EXTRACT(DAY FROM dob)::INTEGER));
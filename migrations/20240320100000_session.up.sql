@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- A session is created when a user verifies an OTP, its token is sent back as a bearer token or a cookie
+CREATE TABLE sessions (
+    token VARCHAR(64) PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
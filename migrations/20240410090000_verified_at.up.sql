@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- An account is verified once the OTP sent at signup is entered, accounts that never are get removed
+-- The accounts created before could already log in, they count as verified
+ALTER TABLE users ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE users ADD COLUMN verified_at TIMESTAMPTZ;
+UPDATE users SET verified_at = created_at;
+CREATE INDEX users_unverified_idx ON users (created_at) WHERE verified_at IS NULL;
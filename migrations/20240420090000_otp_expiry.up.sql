@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- An OTP is refused once it expires or after too many wrong tries, the OTPs sent before have expired
+ALTER TABLE otps ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE otps ALTER COLUMN expires_at DROP DEFAULT;
+ALTER TABLE otps ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
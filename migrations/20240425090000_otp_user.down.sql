@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP INDEX outbox_user_id_idx;
+ALTER TABLE outbox DROP COLUMN user_id;
+DROP INDEX otps_user_id_idx;
+ALTER TABLE otps DROP COLUMN user_id;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- OTPs and their emails belong to the account they were sent for, whatever address they went to,
+-- so deleting the account removes the ones sent before an email change too
+ALTER TABLE otps ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+UPDATE otps SET user_id = users.id FROM users WHERE lower(users.email) = lower(otps.email);
+-- The others were sent to an address no account has anymore
+DELETE FROM otps WHERE user_id IS NULL;
+ALTER TABLE otps ALTER COLUMN user_id SET NOT NULL;
+CREATE INDEX otps_user_id_idx ON otps (user_id);
+
+ALTER TABLE outbox ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+UPDATE outbox SET user_id = users.id FROM users
+WHERE outbox.kind = 'otp' AND lower(users.email) = lower(outbox.recipient);
+DELETE FROM outbox WHERE kind = 'otp' AND user_id IS NULL;
+CREATE INDEX outbox_user_id_idx ON outbox (user_id);
@@ -14,5 +14,28 @@ pub enum Command {
 
     Send,
     Run,
-    Serve,
+    Serve {
+        /// Address the server listens on
+        #[arg(long, env = "HOST", default_value = "0.0.0.0")]
+        host: String,
+        /// Port the server listens on
+        #[arg(long, env = "PORT", default_value_t = 3000)]
+        port: u16,
+    },
+    Retry,
+    /// Remove the accounts that were not verified within UNVERIFIED_ACCOUNT_TTL_HOURS
+    Cleanup,
+    /// Manage the database schema
+    #[command(subcommand)]
+    Migrate(Migrate),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Migrate {
+    /// Apply every pending migration
+    Up,
+    /// Revert the last applied migration
+    Down,
+    /// List the migrations and whether they are applied
+    Status,
 }
@@ -1,13 +1,57 @@
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
 
 use dotenvy::dotenv;
-use sqlx::{Error, PgPool, Pool, Postgres};
+use sqlx::{
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Error,
+};
 
-pub async fn establish_connect() -> Result<Pool<Postgres>, Error> {
+use crate::storage::{memory::MemoryStore, Storage};
+
+// establish_connect creates the database pool shared by the whole process
+// The backend is picked from the DATABASE_URL scheme, `sqlite:` URLs use SQLite
+// (the file is created if missing), `memory:` keeps everything in the process until it exits
+// and everything else is handed to Postgres
+// DATABASE_MAX_CONNECTIONS sets the pool size (default 5) and
+// DATABASE_CONNECT_TIMEOUT the seconds to wait for a connection (default 10), 0 or an invalid value uses the default
+pub async fn establish_connect() -> Result<Storage, Error> {
     dotenv().ok();
-    let database_url =
-        env::var("DATABASE_URL").expect("Please set DATABASE_URL in your environment");
+    let database_url = env::var("DATABASE_URL")
+        .map_err(|_| Error::Configuration("Please set DATABASE_URL in your environment".into()))?;
+    let max_connections = env::var("DATABASE_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(5);
+    let connect_timeout = env::var("DATABASE_CONNECT_TIMEOUT")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(10);
+
+    if database_url.starts_with("memory:") {
+        return Ok(Storage::Memory(MemoryStore::default()));
+    }
+
+    if database_url.starts_with("sqlite:") {
+        let options = SqliteConnectOptions::from_str(&database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .acquire_timeout(Duration::from_secs(connect_timeout))
+            .connect_with(options)
+            .await?;
+        return Ok(Storage::Sqlite(pool));
+    }
 
-    let pool = PgPool::connect(&database_url).await;
-    pool
+    let pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_secs(connect_timeout))
+        .connect(&database_url)
+        .await?;
+    Ok(Storage::Postgres(pool))
 }
@@ -0,0 +1,147 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{Datelike, NaiveDate};
+
+use super::utils::{DEFAULT_BIRTHDAY_SUBJECT, DEFAULT_OTP_SUBJECT};
+
+// Locale is the language used for the emails sent to a friend or user
+// It is stored as a language tag (ex: `en`, `es-MX`) in the database
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+    Fr,
+    Hi,
+}
+
+const MONTHS_EN: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September",
+    "October", "November", "December",
+];
+const MONTHS_ES: [&str; 12] = [
+    "enero", "febrero", "marzo", "abril", "mayo", "junio", "julio", "agosto", "septiembre",
+    "octubre", "noviembre", "diciembre",
+];
+const MONTHS_FR: [&str; 12] = [
+    "janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août", "septembre",
+    "octobre", "novembre", "décembre",
+];
+const MONTHS_HI: [&str; 12] = [
+    "जनवरी", "फ़रवरी", "मार्च", "अप्रैल", "मई", "जून", "जुलाई", "अगस्त", "सितंबर", "अक्टूबर",
+    "नवंबर", "दिसंबर",
+];
+
+impl Locale {
+    pub const OPTIONS: &'static [Locale] = &[Self::En, Self::Es, Self::Fr, Self::Hi];
+
+    // resolve picks the supported locale for a language tag
+    // Region and script subtags are ignored (`es-MX` -> `es`) and anything unknown falls back to English
+    pub fn resolve(tag: &str) -> Locale {
+        let language = tag
+            .trim()
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match language.as_str() {
+            "es" => Locale::Es,
+            "fr" => Locale::Fr,
+            "hi" => Locale::Hi,
+            _ => Locale::En,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Es => "es",
+            Locale::Fr => "fr",
+            Locale::Hi => "hi",
+        }
+    }
+
+    // format_date writes the date the way it is usually written in the language
+    pub fn format_date(&self, date: NaiveDate) -> String {
+        let month = date.month0() as usize;
+        match self {
+            Locale::En => format!("{} {}, {}", MONTHS_EN[month], date.day(), date.year()),
+            Locale::Es => format!("{} de {} de {}", date.day(), MONTHS_ES[month], date.year()),
+            Locale::Fr => format!("{} {} {}", date.day(), MONTHS_FR[month], date.year()),
+            Locale::Hi => format!("{} {} {}", date.day(), MONTHS_HI[month], date.year()),
+        }
+    }
+
+    // purpose translates the `created_for` value of an OTP (Signup, Login, EmailChange)
+    pub fn purpose(&self, created_for: &str) -> String {
+        let purpose = match (self, created_for) {
+            (Locale::En, "Signup") => "sign up",
+            (Locale::En, "Login") => "log in",
+            (Locale::En, "EmailChange") => "confirm your new email",
+            (Locale::Es, "Signup") => "registrarte",
+            (Locale::Es, "Login") => "iniciar sesión",
+            (Locale::Es, "EmailChange") => "confirmar tu nuevo correo",
+            (Locale::Fr, "Signup") => "vous inscrire",
+            (Locale::Fr, "Login") => "vous connecter",
+            (Locale::Fr, "EmailChange") => "confirmer votre nouvelle adresse e-mail",
+            (Locale::Hi, "Signup") => "साइन अप",
+            (Locale::Hi, "Login") => "लॉगिन",
+            (Locale::Hi, "EmailChange") => "नया ईमेल पुष्टि",
+            (_, other) => other,
+        };
+        purpose.to_string()
+    }
+
+    pub fn birthday_subject(&self) -> &'static str {
+        match self {
+            Locale::En => DEFAULT_BIRTHDAY_SUBJECT,
+            Locale::Es => "¡Feliz cumpleaños {name}!",
+            Locale::Fr => "Joyeux anniversaire {name} !",
+            Locale::Hi => "जन्मदिन मुबारक हो {name}!",
+        }
+    }
+
+    pub fn otp_subject(&self) -> &'static str {
+        match self {
+            Locale::En => DEFAULT_OTP_SUBJECT,
+            Locale::Es => "Tu código de acceso de Birthday Wisher",
+            Locale::Fr => "Votre code de connexion Birthday Wisher",
+            Locale::Hi => "आपका Birthday Wisher लॉगिन कोड",
+        }
+    }
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let value: &str = match self {
+            Locale::En => "English",
+            Locale::Es => "Español",
+            Locale::Fr => "Français",
+            Locale::Hi => "हिन्दी",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::Locale;
+
+    #[test]
+    fn test_resolve_falls_back() {
+        assert_eq!(Locale::resolve("es-MX"), Locale::Es);
+        assert_eq!(Locale::resolve(" FR_ca "), Locale::Fr);
+        assert_eq!(Locale::resolve("de"), Locale::En);
+        assert_eq!(Locale::resolve(""), Locale::En);
+    }
+
+    #[test]
+    fn test_format_date() {
+        let date = NaiveDate::from_ymd_opt(2024, 8, 5).unwrap();
+        assert_eq!(Locale::En.format_date(date), "August 5, 2024");
+        assert_eq!(Locale::Es.format_date(date), "5 de agosto de 2024");
+        assert_eq!(Locale::Fr.format_date(date), "5 août 2024");
+    }
+}
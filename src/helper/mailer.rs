@@ -0,0 +1,99 @@
+use std::env;
+use std::time::Duration;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
+};
+use tokio::{
+    sync::Mutex,
+    time::{interval, Interval, MissedTickBehavior},
+};
+
+use crate::server::error::MailError;
+
+use super::utils::sender_name;
+
+// Mailer holds one SMTP transport so every email reuses its connection pool
+// It is cheap to clone and can be shared between tasks
+#[derive(Clone)]
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_env() -> Result<Mailer, MailError> {
+        let smtp_username =
+            env::var("SMTP_USERNAME").map_err(|_| MailError::MissingEnv("SMTP_USERNAME"))?;
+        let smtp_password =
+            env::var("SMTP_PASSWORD").map_err(|_| MailError::MissingEnv("SMTP_PASSWORD"))?;
+        let smtp_host = env::var("SMTP_HOST").map_err(|_| MailError::MissingEnv("SMTP_HOST"))?;
+
+        let from = format!("{} <{}>", sender_name(), smtp_username).parse()?;
+        let creds = Credentials::new(smtp_username, smtp_password);
+        let transport = SmtpTransport::relay(smtp_host.as_str())?
+            .credentials(creds)
+            .build();
+        Ok(Mailer { transport, from })
+    }
+
+    // send delivers the email on a blocking thread so several sends can run at once
+    pub async fn send(&self, to: &str, subject: String, body: String) -> Result<(), MailError> {
+        let email = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_HTML)
+            .body(body)?;
+
+        let transport = self.transport.clone();
+        tokio::task::spawn_blocking(move || transport.send(&email)).await??;
+        Ok(())
+    }
+
+    // test_connection checks that the SMTP server accepts a connection
+    pub async fn test_connection(&self) -> Result<bool, MailError> {
+        let transport = self.transport.clone();
+        Ok(tokio::task::spawn_blocking(move || transport.test_connection()).await??)
+    }
+}
+
+// RateLimiter spaces out sends evenly so we never go over the given messages per minute
+pub struct RateLimiter {
+    interval: Mutex<Interval>,
+}
+
+impl RateLimiter {
+    pub fn per_minute(messages: u32) -> RateLimiter {
+        let mut interval = interval(Duration::from_secs(60) / messages.max(1));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        RateLimiter {
+            interval: Mutex::new(interval),
+        }
+    }
+
+    // wait returns once the next send is allowed
+    pub async fn wait(&self) {
+        self.interval.lock().await.tick().await;
+    }
+}
+
+// SEND_CONCURRENCY is the number of emails sent at the same time (default 4)
+pub fn send_concurrency() -> usize {
+    env::var("SEND_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(4)
+}
+
+// SEND_RATE_PER_MINUTE is the most emails we hand to the SMTP server in a minute (default 60)
+pub fn send_rate_per_minute() -> u32 {
+    env::var("SEND_RATE_PER_MINUTE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(60)
+}
@@ -0,0 +1,157 @@
+use std::{
+    sync::LazyLock,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::storage::Storage;
+
+// REGISTRY holds every metric of the app, /metrics exports it in the Prometheus text format
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests handled, by route and status"),
+        &["method", "route", "status"],
+    ))
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "Time spent handling HTTP requests"),
+        &["method", "route"],
+    ))
+});
+
+static EMAILS_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("emails_sent_total", "Emails delivered, by kind (birthday, otp)"),
+        &["kind"],
+    ))
+});
+
+static EMAILS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("emails_failed_total", "Email delivery attempts that failed, by kind"),
+        &["kind"],
+    ))
+});
+
+static OTP_VERIFICATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("otp_verifications_total", "OTP verifications, by outcome"),
+        &["outcome"],
+    ))
+});
+
+static SCHEDULER_LAST_RUN: LazyLock<Gauge> = LazyLock::new(|| {
+    register(Gauge::new(
+        "scheduler_last_run_timestamp_seconds",
+        "Unix time at which the outbox worker last looked for due emails",
+    ))
+});
+
+static DB_POOL_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("db_pool_connections", "Connections open in the database pool"))
+});
+
+static DB_POOL_IDLE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("db_pool_idle_connections", "Idle connections in the database pool"))
+});
+
+// Outcomes of an OTP verification
+pub const OTP_VERIFIED: &str = "verified";
+pub const OTP_INVALID: &str = "invalid";
+pub const OTP_NOT_FOUND: &str = "not_found";
+pub const OTP_EXPIRED: &str = "expired";
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(
+    metric: prometheus::Result<M>,
+) -> M {
+    // The names and labels are constants, a failure here is a programming error
+    let metric = metric.expect("invalid metric");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+pub fn email_sent(kind: &str) {
+    EMAILS_SENT.with_label_values(&[kind]).inc();
+}
+
+pub fn email_failed(kind: &str) {
+    EMAILS_FAILED.with_label_values(&[kind]).inc();
+}
+
+pub fn otp_verification(outcome: &str) {
+    OTP_VERIFICATIONS.with_label_values(&[outcome]).inc();
+}
+
+pub fn scheduler_ran() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs_f64())
+        .unwrap_or_default();
+    SCHEDULER_LAST_RUN.set(now);
+}
+
+// track_requests counts the requests and their latency per route
+// The route is the matched pattern (ex: /friend/:id) so ids do not create new series
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+// render exports every metric, the database pool is read at scrape time
+pub fn render(pool: &Storage) -> String {
+    if let Some((size, idle)) = pool.pool_stats() {
+        DB_POOL_CONNECTIONS.set(size as i64);
+        DB_POOL_IDLE.set(idle as i64);
+    }
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode the metrics: {}", err);
+    }
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{memory::MemoryStore, Storage};
+
+    use super::{email_sent, otp_verification, render, OTP_VERIFIED};
+
+    #[test]
+    fn test_render_exports_counters() {
+        email_sent("birthday");
+        otp_verification(OTP_VERIFIED);
+
+        let metrics = render(&Storage::Memory(MemoryStore::default()));
+        assert!(metrics.contains("emails_sent_total{kind=\"birthday\"}"));
+        assert!(metrics.contains("otp_verifications_total{outcome=\"verified\"}"));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod db_connection;
+pub mod locale;
+pub mod mailer;
+pub mod metrics;
 pub mod utils;
@@ -1,30 +1,24 @@
 use std::env;
 use std::io::{self, Write};
 
-use chrono::NaiveDate;
+use chrono::{Local, NaiveDate};
 use inquire::validator::Validation;
 use inquire::{formatter::DEFAULT_DATE_FORMATTER, CustomType};
 use inquire::{min_length, Text};
-use lettre::transport::stub::Error;
-use lettre::Transport;
-use lettre::{
-    message::header::ContentType, transport::smtp::authentication::Credentials, Message,
-    SmtpTransport,
-};
-use regex::Regex;
+use email_address::{EmailAddress, Options};
+use serde::{Deserialize, Deserializer};
 
 use crate::schema::friend::InputTypes;
 
+use super::locale::Locale;
+
 pub fn get_text_input(prompt: &str, input_type: InputTypes) -> Option<String> {
     match input_type {
         InputTypes::Text => {
             let ans = Text::new(prompt)
                 .with_validator(min_length!(2, "Minimum 2 letters are required"))
                 .prompt();
-            match ans {
-                Ok(string) => Some(string),
-                Err(_) => None,
-            }
+            ans.ok()
         }
         InputTypes::Date => {
             let date = CustomType::<NaiveDate>::new(prompt)
@@ -32,6 +26,12 @@ pub fn get_text_input(prompt: &str, input_type: InputTypes) -> Option<String> {
                 .with_parser(&|i| NaiveDate::parse_from_str(i, "%d/%m/%Y").map_err(|_e| ()))
                 .with_formatter(DEFAULT_DATE_FORMATTER)
                 .with_error_message("Please type a valid date.")
+                .with_validator(|dob: &NaiveDate| {
+                    Ok(match validate_dob(*dob) {
+                        Ok(()) => Validation::Valid,
+                        Err(message) => Validation::Invalid(message.into()),
+                    })
+                })
                 .prompt();
             match date {
                 Ok(value) => Some(value.to_string()),
@@ -48,11 +48,10 @@ pub fn get_text_input(prompt: &str, input_type: InputTypes) -> Option<String> {
             }
         }
         InputTypes::Email => {
-            let ans = Text::new(prompt).with_validator(val).prompt();
-            match ans {
-                Ok(value) => Some(value),
-                Err(_) => None,
-            }
+            let ans = Text::new(prompt)
+                .with_validator(|value: &str| val(&normalize_email(value)))
+                .prompt();
+            ans.ok().map(|email| normalize_email(&email))
         }
     }
 }
@@ -62,79 +61,171 @@ pub fn clear() {
     io::stdout().flush().unwrap();
 }
 
-fn val(value: &str) -> Result<Validation, Box<dyn std::error::Error + Send + Sync>> {
-    let reg = Regex::new(r"^[\w\.]+@([\w]+\.)+[\w-]{2,4}$");
-    match reg {
-        Ok(reg) => {
-            if !reg.is_match(value) {
-                Ok(Validation::Invalid(
-                    format!("{} is not a valid email", value).as_str().into(),
-                ))
-            } else {
-                Ok(Validation::Valid)
-            }
-        }
-        Err(err) => {
-            println!("{:?}", err);
-            Ok(Validation::Invalid("Sorry Something went wrong".into()))
-        }
+// normalize_email is the form every email is stored and looked up in
+// Surrounding spaces are dropped and the whole address is lowercased, so `Bob@X.com` is `bob@x.com`
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// deserialize_email normalizes an email field of a request body
+pub fn deserialize_email<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(|email| normalize_email(&email))
+}
+
+// Addresses are checked against RFC 5322, without display names (`Bob <bob@x.com>`)
+// or IP literals (`bob@[127.0.0.1]`), and the domain must have a TLD
+const EMAIL_OPTIONS: Options = Options {
+    minimum_sub_domains: 2,
+    allow_domain_literal: false,
+    allow_display_text: false,
+};
+
+// validate_email is the email check shared by the CLI prompts and the API
+// The error is a message that can be shown to the user
+pub fn validate_email(email: &str) -> Result<(), String> {
+    let invalid = |reason: String| format!("{} is not a valid email: {}", email, reason);
+    let (local, domain) = email
+        .rsplit_once('@')
+        .ok_or_else(|| invalid("missing '@'".to_string()))?;
+    let domain = ascii_domain(domain).map_err(invalid)?;
+    EmailAddress::parse_with_options(&format!("{}@{}", local, domain), EMAIL_OPTIONS)
+        .map(|_| ())
+        .map_err(|err| invalid(err.to_string().trim_end_matches('.').to_lowercase()))
+}
+
+// ascii_domain converts an internationalized domain to its punycode form so it can be validated
+#[cfg(feature = "idn")]
+fn ascii_domain(domain: &str) -> Result<String, String> {
+    idna::domain_to_ascii(domain).map_err(|_| "invalid internationalized domain".to_string())
+}
+
+#[cfg(not(feature = "idn"))]
+fn ascii_domain(domain: &str) -> Result<String, String> {
+    if domain.is_ascii() {
+        Ok(domain.to_string())
+    } else {
+        Err("internationalized domains are not supported".to_string())
     }
 }
 
-pub async fn send_email(to: &str, subject: String, body: String) -> Result<(), Error> {
-    let smtp_username =
-        env::var("SMTP_USERNAME").expect("Please set up SMTP_USERNAME in your environment");
-    let from_email = format!("Rahul <{}>", smtp_username.as_str());
-    let smtp_password =
-        env::var("SMTP_PASSWORD").expect("Please set up SMTP_PASSWORD in your environment");
-    let smtp_host = env::var("SMTP_HOST").expect("Please set up SMTP_HOST in your environment");
+// Longest name accepted for a user or a friend
+pub const NAME_MAX_LENGTH: usize = 100;
 
-    let email = Message::builder()
-        .from(from_email.as_str().parse().unwrap())
-        .to(to.parse().unwrap())
-        .subject(subject)
-        .header(ContentType::TEXT_HTML)
-        .body(body)
-        .unwrap();
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err("Name can not be empty".to_string())
+    } else if name.chars().count() > NAME_MAX_LENGTH {
+        Err(format!("Name can not be longer than {} characters", NAME_MAX_LENGTH))
+    } else {
+        Ok(())
+    }
+}
 
-    let creds = Credentials::new(smtp_username.to_owned(), smtp_password.to_owned());
+// Longest language tag the locale columns hold
+pub const LOCALE_MAX_LENGTH: usize = 35;
 
-    let mailer = SmtpTransport::relay(smtp_host.as_str())
-        .unwrap()
-        .credentials(creds)
-        .build();
+// validate_locale accepts any language tag, unsupported languages fall back to English when sending
+pub fn validate_locale(locale: &str) -> Result<(), String> {
+    let locale = locale.trim();
+    if locale.is_empty() || locale.len() > LOCALE_MAX_LENGTH {
+        Err("Locale must be a language tag like en or es-MX".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// validate_dob rejects a date of birth that is still to come
+pub fn validate_dob(dob: NaiveDate) -> Result<(), String> {
+    if dob > Local::now().date_naive() {
+        Err("Date of birth can not be in the future".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_otp(otp: &str) -> Result<(), String> {
+    if otp.is_empty() || !otp.chars().all(|c| c.is_ascii_digit()) {
+        Err("OTP must only contain digits".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn val(value: &str) -> Result<Validation, Box<dyn std::error::Error + Send + Sync>> {
+    match validate_email(value) {
+        Ok(()) => Ok(Validation::Valid),
+        Err(message) => Ok(Validation::Invalid(message.into())),
+    }
+}
+
+// Subject used for birthday wishes when BIRTHDAY_SUBJECT is not set
+pub const DEFAULT_BIRTHDAY_SUBJECT: &str = "Happy Birthday {name}!";
+// Subject used for OTP emails when OTP_SUBJECT is not set
+pub const DEFAULT_OTP_SUBJECT: &str = "Your Birthday Wisher login code";
+
+// sender_name is the display name used in the From header and in templates
+pub fn sender_name() -> String {
+    env::var("SENDER_NAME").unwrap_or_else(|_| "Rahul".to_string())
+}
+
+// subject_template reads the subject template for a locale from the environment
+// It looks for `<KEY>_<LANG>` (ex: BIRTHDAY_SUBJECT_ES), then `<KEY>`, then uses the default
+pub fn subject_template(key: &str, locale: Locale, default: &str) -> String {
+    env::var(format!("{}_{}", key, locale.code().to_uppercase()))
+        .or_else(|_| env::var(key))
+        .unwrap_or_else(|_| default.to_string())
+}
 
-    mailer.send(&email).unwrap();
-    Ok(())
+// render_subject replaces every `{variable}` in the template with its value
+// Unknown variables are left as they are
+pub fn render_subject(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut subject = template.to_string();
+    for (key, value) in vars {
+        subject = subject.replace(&format!("{{{}}}", key), value);
+    }
+    subject
 }
 
 #[cfg(test)]
 mod tests {
     use inquire::validator::Validation;
 
-    use super::val;
+    use super::{normalize_email, render_subject, val, validate_email};
 
     #[test]
     fn test_email_validator() {
         let email1 = "testemial.com";
         let email2 = "test@email.com";
 
-        let test1 = val(email1);
-        match test1 {
-            Ok(result) => match result {
-                Validation::Valid => assert!(false),
-                Validation::Invalid(_) => assert!(true),
-            },
-            Err(_) => assert!(false),
-        }
+        assert!(matches!(val(email1), Ok(Validation::Invalid(_))));
+        assert!(matches!(val(email2), Ok(Validation::Valid)));
 
-        let test2 = val(email2);
-        match test2 {
-            Ok(result) => match result {
-                Validation::Valid => assert!(true),
-                Validation::Invalid(_) => assert!(false),
-            },
-            Err(_) => assert!(false),
+        for valid in ["bob+tag@example.com", "bob@my-domain.travel", "o'neil@mail.example.co.uk"] {
+            assert_eq!(validate_email(valid), Ok(()), "{}", valid);
+        }
+        for invalid in ["bob@localhost", "bob@-example.com", "bob@[127.0.0.1]", "Bob <bob@x.com>", "bob@@x.com"] {
+            assert!(validate_email(invalid).is_err(), "{}", invalid);
         }
+        // Internationalized domains depend on the `idn` feature
+        assert_eq!(validate_email("bob@bücher.de").is_ok(), cfg!(feature = "idn"));
+    }
+
+    #[test]
+    fn test_render_subject() {
+        let subject = render_subject(
+            "Happy {age}th Birthday {name}! - {sender}",
+            &[("name", "Bob"), ("age", "30"), ("sender", "Rahul")],
+        );
+        assert_eq!(subject, "Happy 30th Birthday Bob! - Rahul");
+
+        let subject = render_subject("Code to {purpose} {unknown}", &[("purpose", "Login")]);
+        assert_eq!(subject, "Code to Login {unknown}");
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email("  Bob@Example.COM "), "bob@example.com");
+        assert_eq!(normalize_email("bob@example.com"), "bob@example.com");
     }
 }
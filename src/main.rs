@@ -3,38 +3,77 @@ mod helper;
 mod runner;
 mod schema;
 mod server;
+mod storage;
 
 use clap::Parser;
 use cli::command::{Command, Opts};
 use dotenvy::dotenv;
+use helper::db_connection::establish_connect;
+use runner::migrate;
+use runner::remove_unverified;
+use runner::retry;
 use runner::send;
 use runner::start;
 use server::app;
-use tracing::Level;
-use tracing_subscriber::filter;
+use storage::migrations::auto_migrate;
+use tracing_subscriber::fmt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+// Log filter used when RUST_LOG is not set
+const DEFAULT_LOG_FILTER: &str = "info,tower_http=debug,sqlx::postgres::notice=warn";
 
 #[tokio::main]
 async fn main() {
-    let filter = filter::Targets::new()
-    .with_target("tower_http::trace::on_response", Level::TRACE)
-    .with_target("tower_http::trace::on_request", Level::TRACE)
-    .with_target("tower_http::trace::make_span", Level::DEBUG)
-    .with_default(Level::INFO);
-    let tracing_layer = tracing_subscriber::fmt::layer();
-
-    tracing_subscriber::registry()
-        .with(tracing_layer)
-        .with(filter)
-        .init();
-
     dotenv().ok();
+    init_tracing();
     let opt = Opts::parse();
     let command = opt.command.unwrap_or(Command::Run);
+    // Without its database no command can run, the exit status tells a supervisor or a script
+    let pool = match establish_connect().await {
+        Ok(pool) => pool,
+        Err(err) => {
+            eprintln!("Failed to connect to the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if matches!(command, Command::Run | Command::Serve { .. }) && auto_migrate() {
+        if let Err(err) = pool.migrate_up().await {
+            eprintln!("Failed to migrate the database: {}", err);
+            std::process::exit(1);
+        }
+    }
     match command {
-        Command::Send => {send().await},
-        Command::Run => start().await,
-        Command::Serve => app::serve().await,
+        Command::Send => {send(&pool).await},
+        Command::Run => start(pool).await,
+        Command::Serve { host, port } => {
+            if let Err(err) = app::serve(pool, &host, port).await {
+                eprintln!("{}", err);
+                // A supervisor (systemd, docker) should see that the server did not start
+                std::process::exit(1);
+            }
+        }
+        Command::Retry => retry(&pool).await,
+        Command::Cleanup => remove_unverified(&pool).await,
+        Command::Migrate(command) => migrate(&pool, command).await,
+    }
+}
+
+// init_tracing sets up the logs
+// RUST_LOG filters them (ex: `warn,birthday_wisher=debug`), LOG_FORMAT=json writes one JSON object per line
+fn init_tracing() {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(&directives).unwrap_or_else(|err| {
+            eprintln!("Invalid RUST_LOG ({}), using {}", err, DEFAULT_LOG_FILTER);
+            EnvFilter::new(DEFAULT_LOG_FILTER)
+        }),
+        Err(_) => EnvFilter::new(DEFAULT_LOG_FILTER),
+    };
+    let registry = tracing_subscriber::registry().with(filter);
+    if std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        registry.with(fmt::layer().json().with_current_span(true).with_span_list(false)).init();
+    } else {
+        registry.with(fmt::layer()).init();
     }
 }
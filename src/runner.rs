@@ -1,12 +1,51 @@
-use crate::{helper::utils::{clear, get_text_input}, schema::friend::{Friends, BirthdayWisher, InputTypes}};
+use std::{sync::Arc, time::Duration};
+
+use crate::{cli::command::Migrate, helper::{mailer::{send_concurrency, send_rate_per_minute, Mailer, RateLimiter}, metrics, utils::{clear, get_text_input}}, schema::{friend::{Friends, BirthdayWisher, InputTypes}, outbox::OutboxEntry, user::User}, storage::Storage};
 use inquire::Select;
+use tabled::Table;
+use tokio::{
+    sync::{watch, Semaphore},
+    task::JoinSet,
+};
+use tracing::{error, info, warn};
+
+// Number of outbox entries a worker claims at once
+const OUTBOX_BATCH_SIZE: i64 = 50;
+
+// How often the server looks for accounts that were never verified
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+// send queues today's wishes in the outbox, then delivers everything that is due
+pub async fn send(conn: &Storage){
+    let mailer = match Mailer::from_env() {
+        Ok(mailer) => mailer,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    queue_wishes(conn).await;
+    deliver_due(conn, &mailer).await;
+}
 
-pub async fn send(){
-    let friends = Friends::get_list_of_birthday_friends().await;
+// queue_wishes writes today's wishes to the outbox, each friend is only queued once a day
+pub async fn queue_wishes(conn: &Storage) {
+    let friends = Friends::get_list_of_birthday_friends(conn).await;
     match friends {
         Ok(friends) => {
-            for i in &friends{
-                i.send_birthday_email().await;
+            for friend in &friends {
+                let message = match friend.birthday_message() {
+                    Ok(message) => message,
+                    Err(err) => {
+                        error!("Failed to prepare wishes for {}: {}", friend.email, err);
+                        continue;
+                    }
+                };
+                match message.enqueue(conn).await {
+                    Ok(Some(id)) => info!("Queued wishes for {} as message {}", friend.email, id),
+                    Ok(None) => info!("Wishes for {} are already queued today", friend.email),
+                    Err(err) => error!("Failed to queue wishes for {}: {:?}", friend.email, err),
+                }
             }
         }
         Err(err) => {
@@ -15,9 +54,135 @@ pub async fn send(){
     }
 }
 
+// retry delivers the outbox entries that are due, without queuing anything new
+pub async fn retry(conn: &Storage) {
+    match Mailer::from_env() {
+        Ok(mailer) => deliver_due(conn, &mailer).await,
+        Err(err) => eprintln!("{}", err),
+    }
+}
 
-pub async fn start() {
-    let mut friends = Friends::new();
+// migrate applies, reverts or lists the embedded database migrations
+pub async fn migrate(conn: &Storage, command: Migrate) {
+    match command {
+        Migrate::Up => match conn.migrate_up().await {
+            Ok(_) => println!("The database is up to date"),
+            Err(err) => eprintln!("Failed to migrate the database: {}", err),
+        },
+        Migrate::Down => match conn.migrate_down().await {
+            Ok(Some(version)) => println!("Reverted migration {}", version),
+            Ok(None) => println!("No migration to revert"),
+            Err(err) => eprintln!("Failed to revert the migration: {}", err),
+        },
+        Migrate::Status => match conn.migration_status().await {
+            Ok(status) => println!("{}", Table::new(status)),
+            Err(err) => eprintln!("Failed to read the migrations: {}", err),
+        },
+    }
+}
+
+// outbox_worker delivers queued emails in the background every OUTBOX_POLL_SECONDS (default 10)
+// and removes the accounts that were never verified every hour
+// It stops once shutdown changes, a delivery that already started is finished first
+pub async fn outbox_worker(conn: Storage, mailer: Mailer, mut shutdown: watch::Receiver<bool>) {
+    let seconds = std::env::var("OUTBOX_POLL_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(10);
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+    let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                metrics::scheduler_ran();
+                deliver_due(&conn, &mailer).await;
+            }
+            _ = cleanup.tick() => remove_unverified(&conn).await,
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+// remove_unverified deletes the accounts whose signup OTP was not entered in time
+pub async fn remove_unverified(conn: &Storage) {
+    match User::remove_unverified(conn).await {
+        Ok(0) => {}
+        Ok(count) => info!("Removed {} accounts that were never verified", count),
+        Err(err) => error!("Failed to remove the unverified accounts: {:?}", err),
+    }
+}
+
+// deliver_due sends every outbox entry whose next attempt is due
+// Entries past their deadline are given up first
+pub async fn deliver_due(conn: &Storage, mailer: &Mailer) {
+    match OutboxEntry::give_up_expired(conn).await {
+        Ok(0) => {}
+        Ok(count) => warn!("Gave up on {} emails after their deadline", count),
+        Err(err) => error!("{:?}", err),
+    }
+
+    let semaphore = Arc::new(Semaphore::new(send_concurrency()));
+    let limiter = Arc::new(RateLimiter::per_minute(send_rate_per_minute()));
+    loop {
+        let entries = match OutboxEntry::claim_due(conn, OUTBOX_BATCH_SIZE).await {
+            Ok(entries) if entries.is_empty() => return,
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        };
+        let mut tasks = JoinSet::new();
+        for entry in entries {
+            let mailer = mailer.clone();
+            let semaphore = semaphore.clone();
+            let limiter = limiter.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                limiter.wait().await;
+                let result = mailer
+                    .send(&entry.recipient, entry.subject.clone(), entry.body.clone())
+                    .await;
+                (entry, result)
+            });
+        }
+        while let Some(task) = tasks.join_next().await {
+            let (entry, result) = match task {
+                Ok(task) => task,
+                Err(err) => {
+                    error!("{:?}", err);
+                    continue;
+                }
+            };
+            let result = match result {
+                Ok(_) => {
+                    info!("Sent {} email {} to {}", entry.kind, entry.id, entry.recipient);
+                    metrics::email_sent(&entry.kind);
+                    entry.mark_sent(conn).await
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to send {} email {} to {} (attempt {}): {}",
+                        entry.kind,
+                        entry.id,
+                        entry.recipient,
+                        entry.attempts + 1,
+                        err
+                    );
+                    metrics::email_failed(&entry.kind);
+                    entry.mark_failed(conn, &err.to_string()).await
+                }
+            };
+            if let Err(err) = result {
+                error!("{:?}", err);
+            }
+        }
+    }
+}
+
+pub async fn start(conn: Storage) {
+    let mut friends = Friends::new(conn);
     clear();
     loop {
         let choice = Select::new(
@@ -58,3 +223,41 @@ pub async fn start() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+
+    use crate::{
+        schema::friend::NewFriend,
+        storage::{memory::MemoryStore, FriendRepository, OutboxRepository, Storage},
+    };
+
+    use super::{queue_wishes, OUTBOX_BATCH_SIZE};
+
+    #[tokio::test]
+    async fn test_queue_wishes_once_a_day() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let today = Local::now().date_naive();
+        let birthdays = [
+            ("Asha", "asha@example.com", today),
+            ("Ravi", "ravi@example.com", today.pred_opt().unwrap()),
+        ];
+        for (name, email, dob) in birthdays {
+            let friend = NewFriend {
+                name: name.to_string(),
+                email: email.to_string(),
+                dob,
+                locale: "en".to_string(),
+            };
+            conn.insert_friend(&friend, None).await.unwrap();
+        }
+
+        queue_wishes(&conn).await;
+        queue_wishes(&conn).await;
+
+        let queued = conn.claim_due(OUTBOX_BATCH_SIZE).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].recipient, "asha@example.com");
+    }
+}
@@ -1,45 +1,121 @@
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::Error;
+use utoipa::ToSchema;
 
-use crate::server::error::UserError;
+use crate::{
+    helper::{
+        locale::Locale,
+        utils::{deserialize_email, validate_email, validate_locale, validate_name, validate_otp},
+    },
+    server::error::{UserError, ValidationError},
+    storage::UserRepository,
+};
 
 use super::user::User;
 
-#[derive(Default, Clone, Deserialize)]
+#[derive(Default, Clone, Deserialize, ToSchema)]
 pub struct NewUser {
     pub name: String,
+    #[serde(deserialize_with = "deserialize_email")]
+    #[schema(example = "asha@example.com")]
     pub email: String,
+    // Language of the OTP emails, `en` when missing
+    #[serde(default = "default_locale")]
+    #[schema(example = "en")]
+    pub locale: String,
+}
+
+fn default_locale() -> String {
+    Locale::default().code().to_string()
 }
 
 impl NewUser {
-    pub async fn add(&self, conn: &PgPool) -> Result<User, UserError> {
-        let user = User::get_user_by_email(&conn, &self.email).await;
-        if user.is_ok() {
-            return Err(UserError::UserAlreadyExist);
-        } else {
-            let result = sqlx::query_as!(
-                User,
-                "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING *",
-                self.name,
-                self.email
-            )
-            .fetch_one(conn)
-            .await;
-
-            match result {
-                Ok(result) => Ok(result),
-                Err(err) => Err(UserError::SqlxError(err)),
+    // add inserts the user, the database rejects an email that is already taken
+    pub async fn add(&self, conn: &impl UserRepository) -> Result<User, UserError> {
+        let result = conn.insert_user(self).await;
+        match result {
+            Ok(result) => Ok(result),
+            Err(Error::Database(err)) if err.is_unique_violation() => {
+                Err(UserError::UserAlreadyExist)
             }
+            Err(err) => Err(UserError::SqlxError(err)),
         }
     }
+
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        errors.check("name", validate_name(&self.name));
+        errors.check("email", validate_email(&self.email));
+        errors.check("locale", validate_locale(&self.locale));
+        errors.into_result()
+    }
 }
 
-#[derive(Default, Clone, Debug, Deserialize)]
+#[derive(Default, Clone, Debug, Deserialize, ToSchema)]
 pub struct LoginUser {
+    #[serde(deserialize_with = "deserialize_email")]
     pub email: String,
 }
-#[derive(Default, Clone, Debug, Deserialize)]
+
+impl LoginUser {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        errors.check("email", validate_email(&self.email));
+        errors.into_result()
+    }
+}
+
+
+#[derive(Default, Clone, Debug, Deserialize, ToSchema)]
 pub struct EnteredOtp {
+    #[serde(deserialize_with = "deserialize_email")]
     pub email: String,
     pub otp: String,
 }
+
+impl EnteredOtp {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        errors.check("email", validate_email(&self.email));
+        errors.check("otp", validate_otp(&self.otp));
+        errors.into_result()
+    }
+}
+
+// UpdateUser is the body of PATCH /me, a missing field keeps its value
+#[derive(Default, Clone, Debug, Deserialize, ToSchema)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    // Language of the OTP emails
+    #[schema(example = "es")]
+    pub locale: Option<String>,
+}
+
+impl UpdateUser {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        if let Some(name) = &self.name {
+            errors.check("name", validate_name(name));
+        }
+        if let Some(locale) = &self.locale {
+            errors.check("locale", validate_locale(locale));
+        }
+        errors.into_result()
+    }
+}
+
+// NewEmail is the body of POST /me/email, an OTP is sent to the new address to confirm it
+#[derive(Default, Clone, Debug, Deserialize, ToSchema)]
+pub struct NewEmail {
+    #[serde(deserialize_with = "deserialize_email")]
+    #[schema(example = "asha@work.example")]
+    pub email: String,
+}
+
+impl NewEmail {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        errors.check("email", validate_email(&self.email));
+        errors.into_result()
+    }
+}
@@ -3,43 +3,102 @@ use std::{
     str::FromStr
 };
 
-use crate::{helper::{db_connection::establish_connect, utils::{get_text_input, send_email}}, server::error::FriendError};
+use crate::{helper::{locale::Locale, utils::{deserialize_email, get_text_input, render_subject, sender_name, subject_template, validate_dob, validate_email, validate_locale, validate_name}}, server::error::{FriendError, MailError, ValidationError}};
+
+use super::{
+    outbox::{deadline_for_today, NewMessage, BIRTHDAY},
+    user::User,
+};
 
 use askama::Template;
-use chrono::NaiveDate;
-use inquire::Confirm;
+use chrono::{Datelike, Local, NaiveDate};
+use inquire::{Confirm, Select, Text};
 
 use serde::{Serialize, Deserialize};
 use tabled::{Table, Tabled};
 
-use sqlx::{Error, PgPool};
+use sqlx::{Error, FromRow};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::storage::{day_number, FriendRepository, FriendSearch, Storage};
 
 
 #[derive(Template)]
 #[template(path= "index.html")]
 struct BirthdayTemp<'a> {
+    lang: &'a str,
+    name: &'a str,
+    age: i32,
+    sender: &'a str,
+    date: &'a str,
+}
+
+#[derive(Template)]
+#[template(path= "index.es.html")]
+struct BirthdayTempEs<'a> {
+    lang: &'a str,
     name: &'a str,
+    age: i32,
+    sender: &'a str,
+    date: &'a str,
 }
 
+#[derive(Template)]
+#[template(path= "index.fr.html")]
+struct BirthdayTempFr<'a> {
+    lang: &'a str,
+    name: &'a str,
+    age: i32,
+    sender: &'a str,
+    date: &'a str,
+}
+
+#[derive(Template)]
+#[template(path= "index.hi.html")]
+struct BirthdayTempHi<'a> {
+    lang: &'a str,
+    name: &'a str,
+    age: i32,
+    sender: &'a str,
+    date: &'a str,
+}
 
-#[derive(Default, Tabled, Clone, Debug, Serialize)]
+impl BirthdayTemp<'_> {
+    // render_localized renders the template variant for the locale
+    // The English template (index.html) is the base of every variant and the fallback
+    fn render_localized(&self, locale: Locale) -> askama::Result<String> {
+        let BirthdayTemp { lang, name, age, sender, date } = *self;
+        match locale {
+            Locale::En => self.render(),
+            Locale::Es => BirthdayTempEs { lang, name, age, sender, date }.render(),
+            Locale::Fr => BirthdayTempFr { lang, name, age, sender, date }.render(),
+            Locale::Hi => BirthdayTempHi { lang, name, age, sender, date }.render(),
+        }
+    }
+}
+
+
+#[derive(Default, Tabled, Clone, Debug, Serialize, FromRow, ToSchema)]
 pub struct Friend {
-    id: i32,
-    name: String,
-    email: String,
-    dob: NaiveDate,
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    pub(crate) email: String,
+    pub(crate) dob: NaiveDate,
+    pub(crate) locale: String,
+    // owner_id is the user who added the friend, the friend is removed with their account
+    #[serde(skip)]
+    #[tabled(skip)]
+    pub(crate) owner_id: Option<i32>,
 }
 
 
 impl Friend {
     // get_friend is used to get friend from the database table
     // It takes two argument
-    // 1. conn: &PgPool (Postgres connection) >> It is used to connect with database
+    // 1. conn: &impl FriendRepository (database or in-memory store) >> It is used to connect with database
     // 2. id: i32 >> It is used to get friend with provided id
-    pub async fn get_friend(conn: &PgPool, id: i32) -> Result<Friend, FriendError> {
-        let result = sqlx::query_as!(Friend, "SELECT * FROM friend WHERE id = ($1)", id)
-            .fetch_one(conn)
-            .await;
+    pub async fn get_friend(conn: &impl FriendRepository, id: i32) -> Result<Friend, FriendError> {
+        let result = conn.get_friend(id).await;
         match result {
             Ok(result) => Ok(result),
             Err(err) => {
@@ -52,22 +111,48 @@ impl Friend {
     }
 
     // get_friends is used to get all friends detail from the database table
-    pub async fn get_friends(conn: &PgPool) -> Result<Vec<Friend>, Error> {
-        let friends = sqlx::query_as!(Friend, "SELECT * FROM friend",)
-            .fetch_all(conn)
-            .await;
-        friends
+    // get_owned_friend returns the friend when the user added it, FriendNotFound otherwise
+    // so the friends of other users look like they do not exist
+    pub async fn get_owned_friend(conn: &impl FriendRepository, id: i32, owner: &User) -> Result<Friend, FriendError> {
+        let friend = Self::get_friend(conn, id).await?;
+        if friend.owner_id != Some(owner.id) {
+            return Err(FriendError::FriendNotFound);
+        }
+        Ok(friend)
+    }
+
+    pub async fn get_friends(conn: &impl FriendRepository) -> Result<Vec<Friend>, Error> {
+        conn.get_friends().await
+    }
+
+    // search returns one page of the friends matching the search, with the number of matches
+    // The page starts after the friend the cursor points to, the first page when it is None
+    // With an owner only the friends they added are listed, every friend otherwise
+    pub async fn search(
+        conn: &impl FriendRepository,
+        owner: Option<&User>,
+        search: Option<&str>,
+        sort: FriendSort,
+        limit: i64,
+        after: Option<FriendCursor>,
+    ) -> Result<FriendPage, Error> {
+        // One more friend is read to know whether there is a next page
+        let owner_id = owner.map(|user| user.id);
+        let query = FriendSearch { owner_id, search, sort, limit: limit + 1, after };
+        let mut friends = conn.search_friends(&query, Local::now().date_naive()).await?;
+        let total = conn.count_friends(owner_id, search).await?;
+        let more = friends.len() as i64 > limit;
+        friends.truncate(limit.max(0) as usize);
+        let next_cursor = friends
+            .last()
+            .filter(|_| more)
+            .map(|friend| FriendCursor::after(friend, sort).encode());
+        Ok(FriendPage { friends, total, next_cursor })
     }
 
     // remove_friend is used to remove friend from the database table
-    pub async fn remove_friend(self, conn: &PgPool) -> Result<Friend, FriendError> {
-        let friend = sqlx::query_as!(
-            Friend,
-            "DELETE FROM friend WHERE id = ($1) RETURNING *",
-            self.id
-        )
-        .fetch_one(conn)
-        .await;
+    pub async fn remove_friend(self, conn: &impl FriendRepository) -> Result<Friend, FriendError> {
+        let friend = conn.delete_friend(self.id).await;
         match friend {
             Ok(friend) => Ok(friend),
             Err(err) => {
@@ -79,55 +164,262 @@ impl Friend {
         }
     }
 
-    pub async fn send_birthday_email(&self){
-        let subject = format!("Happy Birthday {}!",self.name);
-        // let body = format!("Happy Birthday {}!", self.name);
-        let body = BirthdayTemp{name: &self.name};
-        send_email(&self.email, subject, body.render().unwrap()).await.unwrap();
+    // age is the number of years the friend turns on the given date
+    pub fn age(&self, on: NaiveDate) -> i32 {
+        let mut age = on.year() - self.dob.year();
+        if self.birthday_in(on.year()).is_some_and(|birthday| on < birthday) {
+            age -= 1;
+        }
+        age
     }
 
-    async fn get_friend_by_email(conn : &PgPool, email : &str) -> Result<Friend, Error>{
-        let result= sqlx::query_as!(
-            Friend,
-            "SELECT * FROM friend WHERE email = $1 ",
-            email
-        )
-        .fetch_one(conn)
-        .await;
-        result
+    // next_birthday is the first birthday of the friend on or after the given date
+    pub fn next_birthday(&self, from: NaiveDate) -> NaiveDate {
+        let birthday_in = |year| self.birthday_in(year).unwrap_or(from);
+        let birthday = birthday_in(from.year());
+        if birthday < from {
+            birthday_in(from.year() + 1)
+        } else {
+            birthday
+        }
+    }
+
+    // birthday_in is the birthday of the friend in the given year
+    // Like the scheduler, February 29 birthdays fall on February 28 when the year is not a leap year
+    fn birthday_in(&self, year: i32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, self.dob.month(), self.dob.day())
+            .or_else(|| NaiveDate::from_ymd_opt(year, 2, 28))
+    }
+
+    // render_wishes renders the subject and the body of the wishes sent on the given date
+    pub fn render_wishes(&self, on: NaiveDate) -> Result<(String, String), MailError> {
+        let locale = Locale::resolve(&self.locale);
+        let age = self.age(on);
+        let sender = sender_name();
+        let date = locale.format_date(on);
+        let subject = render_subject(
+            &subject_template("BIRTHDAY_SUBJECT", locale, locale.birthday_subject()),
+            &[("name", &self.name), ("age", &age.to_string()), ("sender", &sender)],
+        );
+        let body = BirthdayTemp{lang: locale.code(), name: &self.name, age, sender: &sender, date: &date};
+        Ok((subject, body.render_localized(locale)?))
+    }
+
+    // birthday_message renders today's wishes for the friend, ready to be queued in the outbox
+    // It can only be queued once per friend per day
+    pub fn birthday_message(&self) -> Result<NewMessage<'_>, MailError> {
+        let today = Local::now().date_naive();
+        let (subject, body) = self.render_wishes(today)?;
+        Ok(NewMessage {
+            kind: BIRTHDAY,
+            friend_id: Some(self.id),
+            user_id: None,
+            recipient: &self.email,
+            subject,
+            body,
+            deadline: deadline_for_today(),
+            dedupe_key: Some(format!("{}:{}:{}", BIRTHDAY, self.id, today)),
+        })
     }
 
 }
 
-#[derive(Default, Tabled, Clone, Deserialize)]
+#[derive(Default, Tabled, Clone, Deserialize, ToSchema)]
 pub struct NewFriend {
-    name: String,
-    email: String,
-    dob: NaiveDate,
+    pub(crate) name: String,
+    #[serde(deserialize_with = "deserialize_email")]
+    #[schema(example = "ravi@example.com")]
+    pub(crate) email: String,
+    pub(crate) dob: NaiveDate,
+    // Language of the wishes, `en` when missing
+    #[serde(default = "default_locale")]
+    #[schema(example = "en")]
+    pub(crate) locale: String,
 }
 
-impl NewFriend {
-    pub async fn add(&self, conn: &PgPool) -> Result<Friend, FriendError> {
-        let friend = Friend::get_friend_by_email(&conn, &self.email).await;
-        if friend.is_ok() {
-            return Err(FriendError::FriendAlreadyExist);
+fn default_locale() -> String {
+    Locale::default().code().to_string()
+}
+
+// FriendSort is the order of the friend list
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FriendSort {
+    Name,
+    // Birthdays coming soonest first, today's first of all
+    NextBirthday,
+    // The order the friends were added in
+    #[default]
+    Created,
+}
+
+impl FriendSort {
+    pub const OPTIONS: &'static [FriendSort] = &[Self::Created, Self::Name, Self::NextBirthday];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FriendSort::Name => "name",
+            FriendSort::NextBirthday => "next_birthday",
+            FriendSort::Created => "created",
         }
+    }
+}
 
-        let result = sqlx::query_as!(
-            Friend,
-            "INSERT INTO friend (name, email, dob) VALUES($1, $2, $3)  RETURNING *",
-            self.name,
-            self.email,
-            self.dob
-        )
-        .fetch_one(conn)
-        .await;
+impl Display for FriendSort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let value = match self {
+            FriendSort::Name => "Name",
+            FriendSort::NextBirthday => "Next birthday",
+            FriendSort::Created => "Date added",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+// Number of friends in a page when the limit is not given, and the most a page can hold
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+// FriendListQuery is the query string of GET /friend
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FriendListQuery {
+    // Number of friends in the page, 20 when missing, at most 100
+    pub limit: Option<i64>,
+    // next_cursor of the previous page, the first page when missing
+    pub cursor: Option<String>,
+    #[serde(default)]
+    #[param(inline)]
+    pub sort: FriendSort,
+    // Text searched in the name and the email, ignoring case
+    pub q: Option<String>,
+}
+
+impl FriendListQuery {
+    // page returns the limit and the cursor the query asks for
+    // A cursor only continues the list in the sort it was made for
+    pub fn page(&self) -> Result<(i64, Option<FriendCursor>), ValidationError> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let after = match &self.cursor {
+            Some(cursor) => FriendCursor::decode(cursor)
+                .filter(|cursor| cursor.sort() == self.sort)
+                .map(Some),
+            None => Some(None),
+        };
+        let mut errors = ValidationError::default();
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            errors.check("limit", Err(format!("Limit must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+        if after.is_none() {
+            errors.check("cursor", Err("Cursor is not valid".to_string()));
+        }
+        errors.into_result()?;
+        Ok((limit, after.flatten()))
+    }
+
+    // search is the trimmed search text, None when it is empty
+    pub fn search(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+}
+
+// FriendCursor points to the last friend of a page by its sort key and id, the next page starts after it
+// Friends added or removed in the meantime do not shift the pages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "sort", rename_all = "snake_case")]
+pub enum FriendCursor {
+    Created { id: i32 },
+    Name { name: String, id: i32 },
+    // day is the month * 32 + the day of the dob, like the birthday queries count it
+    NextBirthday { day: i32, id: i32 },
+}
+
+impl FriendCursor {
+    // after points to the friend in the given sort
+    pub fn after(friend: &Friend, sort: FriendSort) -> FriendCursor {
+        let id = friend.id;
+        match sort {
+            FriendSort::Created => FriendCursor::Created { id },
+            FriendSort::Name => FriendCursor::Name { name: friend.name.clone(), id },
+            FriendSort::NextBirthday => FriendCursor::NextBirthday { day: day_number(friend.dob), id },
+        }
+    }
+
+    pub fn sort(&self) -> FriendSort {
+        match self {
+            FriendCursor::Created { .. } => FriendSort::Created,
+            FriendCursor::Name { .. } => FriendSort::Name,
+            FriendCursor::NextBirthday { .. } => FriendSort::NextBirthday,
+        }
+    }
+
+    pub fn id(&self) -> i32 {
+        match self {
+            FriendCursor::Created { id } | FriendCursor::Name { id, .. } | FriendCursor::NextBirthday { id, .. } => *id,
+        }
+    }
+
+    // encode is the opaque text sent to the client, hex encoded JSON
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        json.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // decode reads a cursor made by encode, None when it is not one
+    pub fn decode(value: &str) -> Option<FriendCursor> {
+        let bytes = value
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2)?;
+                u8::from_str_radix(pair, 16).ok()
+            })
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+// FriendPage is one page of the friend list
+// next_cursor is passed as `cursor` to get the next page, it is missing on the last page
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FriendPage {
+    pub(crate) friends: Vec<Friend>,
+    // Number of friends matching the search, on every page
+    pub(crate) total: i64,
+    pub(crate) next_cursor: Option<String>,
+}
+
+impl NewFriend {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = ValidationError::default();
+        errors.check("name", validate_name(&self.name));
+        errors.check("email", validate_email(&self.email));
+        errors.check("dob", validate_dob(self.dob));
+        errors.check("locale", validate_locale(&self.locale));
+        errors.into_result()
+    }
+
+    // add inserts the friend, the database rejects an email that is already in the list
+    // A friend added by a logged in user is removed with their account
+    pub async fn add(&self, conn: &impl FriendRepository, owner: Option<&User>) -> Result<Friend, FriendError> {
+        let result = conn.insert_friend(self, owner.map(|user| user.id)).await;
 
         match result {
             Ok(result) =>  Ok(result),
+            Err(Error::Database(err)) if err.is_unique_violation() => Err(FriendError::FriendAlreadyExist),
             Err(err) => Err(FriendError::SqlxError(err))
         }
     }
+
+    // update saves the friend over the one with the given id, keeping the email unique like add
+    pub async fn update(&self, conn: &impl FriendRepository, id: i32) -> Result<Friend, FriendError> {
+        match conn.update_friend(id, self).await {
+            Ok(friend) => Ok(friend),
+            Err(Error::RowNotFound) => Err(FriendError::FriendNotFound),
+            Err(Error::Database(err)) if err.is_unique_violation() => Err(FriendError::FriendAlreadyExist),
+            Err(err) => Err(FriendError::SqlxError(err)),
+        }
+    }
 }
 
 pub enum InputTypes {
@@ -137,16 +429,15 @@ pub enum InputTypes {
     Email,
 }
 
+// Friends runs the friend menu actions on a shared database pool
 #[derive(Clone)]
 pub struct Friends {
-    pub friends: Vec<Friend>,
+    conn: Storage,
 }
 
 impl Friends {
-    pub fn new() -> Friends {
-        Friends {
-            friends: Vec::new(),
-        }
+    pub fn new(conn: Storage) -> Friends {
+        Friends { conn }
     }
 
     pub fn get_friend_info() -> Option<NewFriend> {
@@ -168,100 +459,107 @@ impl Friends {
         } else {
             return None;
         }
+        if let Ok(locale) = Select::new("Which language should the wishes be in?", Locale::OPTIONS.to_vec()).prompt() {
+            friend.locale = locale.code().to_string();
+        } else {
+            return None;
+        }
         Some(friend)
     }
 
     pub async fn add(&mut self, friend: NewFriend) {
-        let connect = establish_connect().await;
-        match connect {
-            Ok(conn) => {
-                let result = friend.add(&conn).await;
-                match result {
-                    Ok(result) => {
-                        println!("New friend is add to the list! \n {:?}", result);
-                    }
-                    Err(err) => {
-                        match err {
-                            FriendError::FriendAlreadyExist => println!("Friend Already exist with this email id {}", friend.email),
-                            _=> println!("Something went wrong!"),
-                        }
-                    },
-                }
+        let result = friend.add(&self.conn, None).await;
+        match result {
+            Ok(result) => {
+                println!("New friend is add to the list! \n {:?}", result);
             }
-            Err(err) => eprintln!("{:?}", err),
+            Err(err) => {
+                match err {
+                    FriendError::FriendAlreadyExist => println!("Friend Already exist with this email id {}", friend.email),
+                    FriendError::SqlxError(err) => eprintln!("Something went wrong! {:?}", err),
+                    _=> println!("Something went wrong!"),
+                }
+            },
         }
-        // self.friends.push(friend)
     }
 
     pub async fn remove(&mut self, id: i32) {
-        let connect = establish_connect().await;
-        match connect {
-            Ok(conn) => {
-                let friend = Friend::get_friend(&conn, id).await;
-                match friend {
-                    Ok(friend) => {
-                        let table = Table::new(vec![friend.clone()]);
-                        println!("{}", table);
-                        let ans = Confirm::new("Do you want to remove this friend")
-                            .with_default(false)
-                            .with_help_message("This will be remove from friend list!")
-                            .prompt();
-
-                        match ans {
-                            Ok(true) => {
-                                let result = friend.remove_friend(&conn).await;
-                                match result {
-                                    Ok(result) => {
-                                        println!("Friend is removed from te list! \n {:?}", result)
-                                    }
-                                    Err(_) => println!("Fail to remove friend!"),
-                                }
+        let friend = Friend::get_friend(&self.conn, id).await;
+        match friend {
+            Ok(friend) => {
+                let table = Table::new(vec![friend.clone()]);
+                println!("{}", table);
+                let ans = Confirm::new("Do you want to remove this friend")
+                    .with_default(false)
+                    .with_help_message("This will be remove from friend list!")
+                    .prompt();
+
+                match ans {
+                    Ok(true) => {
+                        let result = friend.remove_friend(&self.conn).await;
+                        match result {
+                            Ok(result) => {
+                                println!("Friend is removed from te list! \n {:?}", result)
                             }
-                            Ok(false) => println!("Ok!"),
-                            Err(_) => println!("Something went wrong!"),
+                            Err(_) => println!("Fail to remove friend!"),
                         }
                     }
-                    Err(err) =>{
-                        match err  {
-                            FriendError::FriendNotFound => println!("Friend Not found!"),
-                            _ => println!("Something went wrong!")
-                        }
-                    },
+                    Ok(false) => println!("Ok!"),
+                    Err(_) => println!("Something went wrong!"),
                 }
             }
-            Err(err) => eprintln!("{:?}", err),
+            Err(err) =>{
+                match err  {
+                    FriendError::FriendNotFound => println!("Friend Not found!"),
+                    _ => println!("Something went wrong!")
+                }
+            },
         }
     }
 
+    // show_friends prints the friends a page at a time, filtered and sorted like GET /friend
     pub async fn show_friends(&self) {
-        let connect = establish_connect().await;
-        match connect {
-            Ok(connect) => {
-                let friends = Friend::get_friends(&connect).await;
-                match friends {
-                    Ok(result) => {
-                        let table = Table::new(result.clone()).to_string();
-                        println!("{}", table);
-                    }
-                    Err(_) => println!("Something went wrong, Please try again!"),
+        let search = Text::new("Search by name or email")
+            .with_help_message("Leave empty to show every friend")
+            .prompt()
+            .unwrap_or_default();
+        let search = Some(search.trim()).filter(|search| !search.is_empty());
+        let Ok(sort) = Select::new("Sort by", FriendSort::OPTIONS.to_vec()).prompt() else {
+            return;
+        };
+        let mut after = None;
+        let mut shown = 0;
+        loop {
+            let page = Friend::search(&self.conn, None, search, sort, DEFAULT_PAGE_SIZE, after).await;
+            let page = match page {
+                Ok(page) => page,
+                Err(_) => {
+                    println!("Something went wrong, Please try again!");
+                    return;
                 }
+            };
+            if page.friends.is_empty() {
+                println!("No friend found!");
+                return;
+            }
+            let first = shown + 1;
+            shown += page.friends.len();
+            println!("{}", Table::new(&page.friends));
+            println!("Showing {} to {} of {} friends", first, shown, page.total);
+            let Some(last) = page.friends.last().filter(|_| page.next_cursor.is_some()) else {
+                return;
+            };
+            let more = Confirm::new("Show the next page?").with_default(true).prompt();
+            if !matches!(more, Ok(true)) {
+                return;
             }
-            Err(err) => eprintln!("{:?}",err),
+            after = Some(FriendCursor::after(last, sort));
         }
     }
 
-    pub async fn get_list_of_birthday_friends()-> Result<Vec<Friend>,Error>{
-        let connect = establish_connect().await?;
-        let friends = sqlx::query_as!(
-            Friend,
-            r#"
-            SELECT * FROM friend
-            WHERE EXTRACT(MONTH FROM dob) = EXTRACT(MONTH FROM CURRENT_DATE)
-            AND EXTRACT(DAY FROM dob) = EXTRACT(DAY FROM CURRENT_DATE)
-            "#,
-        ).fetch_all(&connect)
-        .await;
-    friends
+    // get_list_of_birthday_friends returns the friends whose birthday is today in local time
+    pub async fn get_list_of_birthday_friends(conn: &impl FriendRepository)-> Result<Vec<Friend>,Error>{
+        conn.get_birthday_friends(Local::now().date_naive()).await
     }
 }
 
@@ -297,3 +595,32 @@ impl Display for BirthdayWisher {
         write!(f, "{}", value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::Friend;
+
+    #[test]
+    fn test_leap_day_birthday() {
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+        let friend = Friend {
+            dob: date(2000, 2, 29),
+            ..Friend::default()
+        };
+        // The wishes are sent on February 28 in a year that is not a leap year, the age must agree
+        assert_eq!(friend.next_birthday(date(2027, 1, 10)), date(2027, 2, 28));
+        assert_eq!(friend.age(date(2027, 2, 27)), 26);
+        assert_eq!(friend.age(date(2027, 2, 28)), 27);
+        assert_eq!(friend.age(date(2028, 2, 28)), 27);
+        assert_eq!(friend.age(date(2028, 2, 29)), 28);
+
+        let friend = Friend {
+            dob: date(1990, 8, 5),
+            ..Friend::default()
+        };
+        assert_eq!(friend.age(date(2026, 8, 4)), 35);
+        assert_eq!(friend.age(date(2026, 8, 5)), 36);
+    }
+}
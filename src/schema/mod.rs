@@ -1,4 +1,6 @@
 pub mod friend;
 pub mod user;
 pub mod otps;
+pub mod outbox;
 pub mod api;
+pub mod session;
@@ -1,69 +1,152 @@
+use std::env;
+
 use askama::Template;
-use lettre::transport::smtp::Error as SmtpError;
+use chrono::{DateTime, Duration, Local, Utc};
 use rand::Rng;
-use sqlx::{Error as SqlxError, PgPool};
+use sqlx::{Error as SqlxError, FromRow};
+
+use crate::{
+    helper::{
+        locale::Locale,
+        utils::{render_subject, sender_name, subject_template},
+    },
+    server::error::MailError,
+    storage::OtpRepository,
+};
 
-use crate::helper::utils::send_email;
+use super::outbox::{otp_deadline, NewMessage, OTP};
 
+// What an OTP was sent for, stored in `created_for`
+pub const SIGNUP: &str = "Signup";
+pub const LOGIN: &str = "Login";
+// EMAIL_CHANGE OTPs are sent to the new address of a user
+pub const EMAIL_CHANGE: &str = "EmailChange";
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Otp {
-    email: String,
-    otp: String,
-    created_for: String,
-    used: bool,
-    sent: bool,
+    // id is given by the database when the OTP is saved
+    pub(crate) id: i32,
+    // user_id is the account the OTP was sent for, an email change OTP goes to an address it does not have yet
+    pub(crate) user_id: i32,
+    pub(crate) email: String,
+    pub(crate) otp: String,
+    pub(crate) created_for: String,
+    pub(crate) used: bool,
+    pub(crate) expires_at: DateTime<Utc>,
+    // attempts counts the wrong codes entered for this OTP
+    pub(crate) attempts: i32,
 }
 
 #[derive(Template)]
 #[template(path = "otp.html")]
 pub struct OtpTemp<'a> {
+    pub(crate) lang: &'a str,
     pub(crate) otp: &'a str,
-    pub(crate) used_for: &'a str,
+    pub(crate) used_for: String,
+    pub(crate) sender: String,
+    pub(crate) date: String,
+}
+
+#[derive(Template)]
+#[template(path = "otp.es.html")]
+struct OtpTempEs<'a> {
+    lang: &'a str,
+    otp: &'a str,
+    used_for: &'a str,
+    sender: &'a str,
+    date: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "otp.fr.html")]
+struct OtpTempFr<'a> {
+    lang: &'a str,
+    otp: &'a str,
+    used_for: &'a str,
+    sender: &'a str,
+    date: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "otp.hi.html")]
+struct OtpTempHi<'a> {
+    lang: &'a str,
+    otp: &'a str,
+    used_for: &'a str,
+    sender: &'a str,
+    date: &'a str,
+}
+
+impl OtpTemp<'_> {
+    // render_localized renders the template variant for the locale
+    // The English template (otp.html) is the base of every variant and the fallback
+    pub fn render_localized(&self, locale: Locale) -> askama::Result<String> {
+        let (lang, otp, used_for, sender, date) =
+            (self.lang, self.otp, self.used_for.as_str(), self.sender.as_str(), self.date.as_str());
+        match locale {
+            Locale::En => self.render(),
+            Locale::Es => OtpTempEs { lang, otp, used_for, sender, date }.render(),
+            Locale::Fr => OtpTempFr { lang, otp, used_for, sender, date }.render(),
+            Locale::Hi => OtpTempHi { lang, otp, used_for, sender, date }.render(),
+        }
+    }
 }
 impl Otp {
-    pub async fn new(
-        email: String,
-        used_for: String,
-        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<Self, SqlxError> {
-        let otp = Self::gen_otp().to_string();
-        let otp = Self {
+    // generate creates a new OTP for the email, it is saved when it is sent and lasts OTP_TTL_MINUTES
+    pub fn generate(user_id: i32, email: String, used_for: String) -> Self {
+        Self {
+            id: 0,
+            user_id,
             email,
-            otp,
+            otp: Self::gen_otp().to_string(),
             created_for: used_for,
             used: false,
-            sent: false,
-        };
-
-        sqlx::query_as!(
-            Otp,
-            "INSERT INTO otps (email, otp, created_for, used, sent) VALUES ($1, $2, $3, $4, $5) RETURNING *",
-            otp.email,
-            otp.otp,
-            otp.created_for,
-            otp.used,
-            otp.sent
-        )
-        .fetch_one(&mut **transaction)
-        .await
+            expires_at: Utc::now() + Duration::minutes(otp_ttl_minutes()),
+            attempts: 0,
+        }
     }
 
     fn gen_otp() -> i32 {
-        rand::thread_rng().gen_range(1000..10000)
+        rand::thread_rng().gen_range(100000..1000000)
     }
 
-    pub fn get_opt_template(&self) -> OtpTemp {
+    // is_spent tells whether the OTP expired or was guessed wrong too many times, it can not be used anymore
+    pub fn is_spent(&self) -> bool {
+        self.expires_at <= Utc::now() || self.attempts >= otp_max_attempts()
+    }
+
+    pub fn get_opt_template(&self, locale: Locale) -> OtpTemp<'_> {
         OtpTemp {
+            lang: locale.code(),
             otp: &self.otp,
-            used_for: &self.created_for,
+            used_for: locale.purpose(&self.created_for),
+            sender: sender_name(),
+            date: locale.format_date(Local::now().date_naive()),
         }
     }
 
-    pub async fn send_otp(&mut self) -> Result<(), SmtpError> {
-        let body = self.get_opt_template().render().unwrap();
-        send_email(&self.email, "otp".to_string(), body.to_string())
-            .await
-            .unwrap();
-        Ok(())
+    // send_otp saves the OTP and queues its email in the outbox
+    // Both happen in one transaction, the email is only sent once it is committed
+    pub async fn send_otp(self, locale: Locale, conn: &impl OtpRepository) -> Result<i32, MailError> {
+        let template = self.get_opt_template(locale);
+        let subject = render_subject(
+            &subject_template("OTP_SUBJECT", locale, locale.otp_subject()),
+            &[("purpose", &template.used_for), ("sender", &template.sender)],
+        );
+        let body = template.render_localized(locale)?;
+        let message = NewMessage {
+            kind: OTP,
+            friend_id: None,
+            user_id: Some(self.user_id),
+            recipient: &self.email,
+            subject,
+            body,
+            deadline: otp_deadline(),
+            dedupe_key: None,
+        };
+        conn.save_otp_with_message(&self, &message)
+            .await?
+            .ok_or(MailError::NotQueued)
     }
 
     pub async fn verify_otp(&mut self, otp: String) -> bool {
@@ -73,47 +156,38 @@ impl Otp {
         false
     }
 
-    pub async fn otp_sent(
-        &mut self,
-        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<(), SqlxError> {
-        sqlx::query!(
-            "UPDATE otps SET sent = $1 WHERE email = $2 AND otp = $3",
-            true,
-            self.email,
-            self.otp
-        )
-        .execute(&mut **transaction)
-        .await?;
-        Ok(())
+    pub async fn otp_used(&mut self, conn: &impl OtpRepository) -> Result<(), SqlxError> {
+        conn.delete_otp(self).await
     }
 
-    pub async fn otp_used(
-        &mut self,
-        pool: &PgPool,
-    ) -> Result<(), SqlxError> {
-        sqlx::query!(
-            "DELETE FROM otps WHERE email = $1 AND otp = $2",
-            self.email,
-            self.otp
-        )
-        .execute(pool)
-        .await?;
+    // wrong_attempt counts a wrong code, the OTP is deleted once OTP_MAX_ATTEMPTS are reached
+    pub async fn wrong_attempt(&mut self, conn: &impl OtpRepository) -> Result<(), SqlxError> {
+        self.attempts = conn.add_otp_attempt(self).await?;
+        if self.is_spent() {
+            conn.delete_otp(self).await?;
+        }
         Ok(())
     }
 
-    pub async fn get_otp(email: String, pool: &PgPool) -> Result<Otp, SqlxError> {
-        let result = sqlx::query_as!(
-            Otp,
-            "SELECT * FROM otps WHERE email = $1 ", email)
-            .fetch_one(pool)
-            .await;
-        match result {
-            Ok(result) => Ok(result),
-            Err(err) => match err {
-                sqlx::Error::RowNotFound => Err(err),
-                _ => Err(err),
-            },
-        }
+    pub async fn get_otp(email: String, conn: &impl OtpRepository) -> Result<Otp, SqlxError> {
+        conn.get_otp(&email).await
     }
 }
+
+// OTP_TTL_MINUTES is how long an OTP can be entered after it was sent (default 10)
+pub fn otp_ttl_minutes() -> i64 {
+    env::var("OTP_TTL_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(10)
+}
+
+// OTP_MAX_ATTEMPTS is how many wrong codes are accepted before the OTP is deleted (default 5)
+pub fn otp_max_attempts() -> i32 {
+    env::var("OTP_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(5)
+}
@@ -0,0 +1,182 @@
+use std::env;
+
+use chrono::{DateTime, Duration, Local, Utc};
+use serde::Serialize;
+use sqlx::{Error, FromRow};
+use utoipa::ToSchema;
+
+use crate::storage::OutboxRepository;
+
+// Status of an outbox entry
+pub const PENDING: &str = "pending";
+pub const SENT: &str = "sent";
+pub const FAILED: &str = "failed";
+
+// Kind of message in the outbox
+pub const BIRTHDAY: &str = "birthday";
+pub const OTP: &str = "otp";
+
+// NewMessage is an email to be written to the outbox
+// It should be enqueued in the same transaction as the change that triggers it,
+// the outbox worker delivers it once the transaction is committed
+pub struct NewMessage<'a> {
+    pub(crate) kind: &'a str,
+    pub(crate) friend_id: Option<i32>,
+    // user_id is the account an OTP email is sent for
+    pub(crate) user_id: Option<i32>,
+    pub(crate) recipient: &'a str,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+    pub(crate) deadline: DateTime<Utc>,
+    // Messages with the same dedupe_key are only queued once (ex: one wish per friend per day)
+    pub(crate) dedupe_key: Option<String>,
+}
+
+// OutboxEntry is a queued email claimed by a worker for delivery
+#[derive(FromRow)]
+pub struct OutboxEntry {
+    pub(crate) id: i32,
+    pub(crate) kind: String,
+    pub(crate) recipient: String,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+    pub(crate) attempts: i32,
+    pub(crate) deadline: DateTime<Utc>,
+}
+
+// MessageStatus is the delivery status of a message, without its content
+// The endpoint is public and ids are sequential, so neither the recipient nor the SMTP error is included
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct MessageStatus {
+    pub(crate) id: i32,
+    pub(crate) kind: String,
+    pub(crate) status: String,
+    pub(crate) attempts: i32,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) sent_at: Option<DateTime<Utc>>,
+}
+
+// backoff is the time to wait before the next attempt
+// It starts at `base_seconds` and doubles after every failed attempt
+pub fn backoff(attempts: i32, base_seconds: i64) -> Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    Duration::seconds(base_seconds.saturating_mul(1 << exponent))
+}
+
+// RETRY_BASE_SECONDS is the wait after the first failure (default 60 seconds)
+fn retry_base_seconds() -> i64 {
+    env::var("RETRY_BASE_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(60)
+}
+
+// deadline_for_today is when we give up on today's wishes
+// RETRY_DEADLINE_HOURS is counted from the start of the day in local time,
+// the default of 24 hours means we keep trying until the birthday is over
+pub fn deadline_for_today() -> DateTime<Utc> {
+    let hours = env::var("RETRY_DEADLINE_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(24);
+    let start_of_day = Local::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    start_of_day + Duration::hours(hours)
+}
+
+// otp_deadline is when we stop trying to deliver an OTP
+// OTP_DELIVERY_MINUTES defaults to 15 minutes, after that the user has most likely asked again
+pub fn otp_deadline() -> DateTime<Utc> {
+    let minutes = env::var("OTP_DELIVERY_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(15);
+    Utc::now() + Duration::minutes(minutes)
+}
+
+impl NewMessage<'_> {
+    // enqueue writes the message to the outbox and returns its id
+    // It returns None when a message with the same dedupe_key is already queued
+    pub async fn enqueue(&self, conn: &impl OutboxRepository) -> Result<Option<i32>, Error> {
+        conn.enqueue(self).await
+    }
+}
+
+impl OutboxEntry {
+    // claim_due picks up to `limit` pending entries whose next attempt time has come
+    // The claimed entries are pushed back by a lease so other workers skip them,
+    // if this worker dies they are picked up again once the lease is over
+    pub async fn claim_due(conn: &impl OutboxRepository, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        conn.claim_due(limit).await
+    }
+
+    // give_up_expired marks every pending entry past its deadline as failed
+    pub async fn give_up_expired(conn: &impl OutboxRepository) -> Result<u64, Error> {
+        conn.give_up_expired().await
+    }
+
+    pub async fn mark_sent(&self, conn: &impl OutboxRepository) -> Result<(), Error> {
+        conn.mark_sent(self.id).await
+    }
+
+    // mark_failed schedules the next attempt, or gives up when it would be after the deadline
+    pub async fn mark_failed(&self, conn: &impl OutboxRepository, error: &str) -> Result<(), Error> {
+        let attempts = self.attempts + 1;
+        let next_attempt_at = Utc::now() + backoff(attempts, retry_base_seconds());
+        let status = if next_attempt_at < self.deadline { PENDING } else { FAILED };
+        conn.mark_failed(self.id, status, attempts, error, next_attempt_at)
+            .await
+    }
+
+    pub async fn get_status(conn: &impl OutboxRepository, id: i32) -> Result<MessageStatus, Error> {
+        conn.get_status(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use crate::storage::{memory::MemoryStore, OutboxRepository};
+
+    use super::{backoff, NewMessage, BIRTHDAY, OTP};
+
+    #[test]
+    fn test_backoff_doubles() {
+        assert_eq!(backoff(1, 60), Duration::seconds(60));
+        assert_eq!(backoff(2, 60), Duration::seconds(120));
+        assert_eq!(backoff(4, 60), Duration::seconds(480));
+        assert_eq!(backoff(100, 60), Duration::seconds(60 * 65536));
+    }
+
+    #[tokio::test]
+    async fn test_claim_due_sends_otp_first() {
+        let conn = MemoryStore::default();
+        let message = |kind, recipient| NewMessage {
+            kind,
+            friend_id: None,
+            user_id: None,
+            recipient,
+            subject: "Hello".to_string(),
+            body: "Hello".to_string(),
+            deadline: Utc::now() + Duration::hours(1),
+            dedupe_key: None,
+        };
+        conn.enqueue(&message(BIRTHDAY, "ravi@example.com")).await.unwrap();
+        conn.enqueue(&message(BIRTHDAY, "kim@example.com")).await.unwrap();
+        conn.enqueue(&message(OTP, "asha@example.com")).await.unwrap();
+
+        let claimed = conn.claim_due(2).await.unwrap();
+        let recipients: Vec<&str> = claimed.iter().map(|entry| entry.recipient.as_str()).collect();
+        assert_eq!(recipients, ["asha@example.com", "ravi@example.com"]);
+    }
+}
@@ -0,0 +1,49 @@
+use std::env;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Error as SqlxError, FromRow};
+use uuid::Uuid;
+
+use crate::storage::SessionRepository;
+
+use super::user::User;
+
+// Session is opened when a user verifies an OTP
+// The token is sent back as a bearer token by API clients and as a cookie by the web UI
+#[derive(Clone, Debug, FromRow)]
+pub struct Session {
+    pub(crate) token: String,
+    pub(crate) user_id: i32,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+impl Session {
+    // start opens a new session for the user, it lasts SESSION_TTL_HOURS
+    pub async fn start(conn: &impl SessionRepository, user: &User) -> Result<Session, SqlxError> {
+        let session = Session {
+            token: Uuid::new_v4().simple().to_string(),
+            user_id: user.id,
+            expires_at: Utc::now() + Duration::hours(session_ttl_hours()),
+        };
+        conn.insert_session(&session).await?;
+        Ok(session)
+    }
+
+    // user returns the user of the session, RowNotFound when the token is unknown or expired
+    pub async fn user(conn: &impl SessionRepository, token: &str) -> Result<User, SqlxError> {
+        conn.get_session_user(token).await
+    }
+
+    pub async fn end(conn: &impl SessionRepository, token: &str) -> Result<(), SqlxError> {
+        conn.delete_session(token).await
+    }
+}
+
+// SESSION_TTL_HOURS is how long a session stays valid after the OTP was verified (default 168, a week)
+pub fn session_ttl_hours() -> i64 {
+    env::var("SESSION_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(168)
+}
@@ -1,22 +1,32 @@
+use std::env;
+
+use chrono::{DateTime, Duration, Utc};
 use serde:: Serialize;
-use sqlx::{Error, PgPool};
+use sqlx::{Error, FromRow};
+use utoipa::ToSchema;
 
-use crate::server::error::{ApiError, UserError};
+use crate::{
+    helper::locale::Locale,
+    server::error::{ApiError, MailError, UserError},
+    storage::{OtpRepository, UserRepository},
+};
 
-use super::otps::Otp;
+use super::{api::UpdateUser, otps::Otp};
 
-#[derive(Default, Clone, Debug, Serialize)]
+#[derive(Default, Clone, Debug, Serialize, FromRow, ToSchema)]
 pub struct User {
     pub(crate) id: i32,
     pub(crate) name: String,
     pub(crate) email: String,
+    pub(crate) locale: String,
+    pub(crate) created_at: DateTime<Utc>,
+    // verified_at is set once the OTP sent at signup is entered
+    pub(crate) verified_at: Option<DateTime<Utc>>,
 }
 
 impl User {
-    pub async fn get_user_by_email(conn: &PgPool, email: &str) -> Result<User, UserError> {
-        let result = sqlx::query_as!(User, "SELECT * FROM users WHERE email = $1 ", email)
-            .fetch_one(conn)
-            .await;
+    pub async fn get_user_by_email(conn: &impl UserRepository, email: &str) -> Result<User, UserError> {
+        let result = conn.get_user_by_email(email).await;
         match result {
             Ok(result) => Ok(result),
             Err(err) => match err {
@@ -26,50 +36,83 @@ impl User {
         }
     }
 
-    pub async fn send_otp(self, used_for: String, conn: &PgPool) -> Result<(), ApiError> {
-        let transaction_result = conn.begin().await;
-        if let Ok(mut transaction_ok) = transaction_result {
-            let otp = Otp::new(self.email, used_for, &mut transaction_ok).await;
-            if let Ok(mut otp_ok) = otp {
-                match otp_ok.send_otp().await {
-                    Ok(_) => {
-                        match otp_ok.otp_sent(&mut transaction_ok).await {
-                            Ok(_) => match transaction_ok.commit().await {
-                                Ok(_) => return Ok(()),
-                                Err(_) => {
-                                    return Err(ApiError::TransactionError(
-                                        "Failed to commit transaction".to_string(),
-                                    ))
-                                }
-                            }, // otp sent ok
-                            Err(_) => return Err(ApiError::InternalServerError),
-                        } // otp sent
-                    } // send otp ok
-                    Err(_) => match transaction_ok.rollback().await {
-                        Ok(_) => return Err(ApiError::EmailError),
-                        Err(_) => {
-                            return Err(ApiError::TransactionError(
-                                "Failed to rollback transaction".to_string(),
-                            ))
-                        }
-                    },
-                } // send otp
-            }
-            // otp ok
-            else {
-                match transaction_ok.rollback().await {
-                    Ok(_) => return Err(ApiError::InternalServerError),
-                    Err(_) => {
-                        return Err(ApiError::TransactionError(
-                            "Failed to rollback transaction".to_string(),
-                        ))
-                    }
-                }
-            } // otp not ok
-        } else {
-            return Err(ApiError::TransactionError(
-                "Failed to start transaction".to_string(),
-            ));
+    // get_user_for_login finds the user logging in, an account that was never verified may not log in
+    pub async fn get_user_for_login(conn: &impl UserRepository, email: &str) -> Result<User, UserError> {
+        let user = User::get_user_by_email(conn, email).await?;
+        if !user.is_verified() {
+            return Err(UserError::UserNotVerified);
+        }
+        Ok(user)
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    // verify marks the account as verified, it is called when the signup OTP is entered
+    pub async fn verify(&self, conn: &impl UserRepository) -> Result<User, UserError> {
+        match conn.verify_user(self.id).await {
+            Ok(user) => Ok(user),
+            Err(Error::RowNotFound) => Err(UserError::UserNotFound),
+            Err(err) => Err(UserError::SqlxError(err)),
+        }
+    }
+
+    // remove_unverified deletes the accounts that were not verified within UNVERIFIED_ACCOUNT_TTL_HOURS
+    // It returns how many were removed
+    pub async fn remove_unverified(conn: &impl UserRepository) -> Result<usize, UserError> {
+        let created_before = Utc::now() - Duration::hours(unverified_account_ttl_hours());
+        let users = conn.get_unverified_users(created_before).await.map_err(UserError::SqlxError)?;
+        let count = users.len();
+        for user in users {
+            user.delete(conn).await?;
         }
+        Ok(count)
     }
+
+    // update saves the profile fields that are set
+    pub async fn update(&self, conn: &impl UserRepository, update: &UpdateUser) -> Result<User, UserError> {
+        match conn.update_user(self.id, update).await {
+            Ok(user) => Ok(user),
+            Err(Error::RowNotFound) => Err(UserError::UserNotFound),
+            Err(err) => Err(UserError::SqlxError(err)),
+        }
+    }
+
+    // change_email moves the account to a confirmed address, another account may not have it
+    pub async fn change_email(&self, conn: &impl UserRepository, email: &str) -> Result<User, UserError> {
+        match conn.update_user_email(self.id, email).await {
+            Ok(user) => Ok(user),
+            Err(Error::RowNotFound) => Err(UserError::UserNotFound),
+            Err(Error::Database(err)) if err.is_unique_violation() => Err(UserError::UserAlreadyExist),
+            Err(err) => Err(UserError::SqlxError(err)),
+        }
+    }
+
+    // delete removes the account and everything that belongs to it
+    pub async fn delete(self, conn: &impl UserRepository) -> Result<(), UserError> {
+        conn.delete_user(&self).await.map_err(UserError::SqlxError)
+    }
+
+    // send_otp creates an OTP and queues its email in one transaction
+    // It returns the id of the queued message, its delivery status is available from the outbox
+    pub async fn send_otp(self, used_for: String, conn: &impl OtpRepository) -> Result<i32, ApiError> {
+        let locale = Locale::resolve(&self.locale);
+        let otp = Otp::generate(self.id, self.email, used_for);
+        otp.send_otp(locale, conn).await.map_err(|err| match err {
+            MailError::Queue(_) => {
+                ApiError::TransactionError("Failed to save the OTP".to_string())
+            }
+            _ => ApiError::EmailError,
+        })
+    }
+}
+
+// UNVERIFIED_ACCOUNT_TTL_HOURS is how long an account may wait for its signup OTP before it is removed (default 72)
+pub fn unverified_account_ttl_hours() -> i64 {
+    env::var("UNVERIFIED_ACCOUNT_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(72)
 }
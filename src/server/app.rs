@@ -1,24 +1,88 @@
-use axum::Router;
+use std::net::SocketAddr;
 
+use axum::{middleware, Router};
+
+use tokio::{signal, sync::watch};
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
 use crate::{
-    helper::db_connection::establish_connect, server::{friend_route::friend_route, handler::handler_404, public_route::public_route}
+    helper::{mailer::Mailer, metrics::track_requests}, runner::outbox_worker, server::{docs_route::docs_route, error::ServeError, friend_route::friend_route, health_route::{health_route, HealthState}, handler::handler_404, layers::{request_span, with_http_layers, CorsConfig}, me_route::me_route, outbox_route::outbox_route, public_route::public_route, rate_limit::AuthLimits, web_route::web_route}, storage::Storage
 };
 
-pub async fn serve() {
-    let pool = establish_connect().await.unwrap();
+pub async fn serve(pool: Storage, host: &str, port: u16) -> Result<(), ServeError> {
+    let mailer = Mailer::from_env()?;
+    let cors = CorsConfig::from_env().layer()?;
+    let limits = AuthLimits::from_env();
+    let address = format!("{}:{}", host, port);
+    let listener = tokio::net::TcpListener::bind(&address)
+        .await
+        .map_err(|err| ServeError::Bind(address, err))?;
+
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let worker = tokio::spawn(outbox_worker(pool.clone(), mailer.clone(), shutdown_rx));
+    let health = HealthState {
+        pool: pool.clone(),
+        mailer,
+    };
     let app = Router::new()
         .nest("/friend", friend_route())
-        .nest("/", public_route())
+        .nest("/outbox", outbox_route())
+        .nest("/me", me_route(limits.clone()))
+        .nest("/ui", web_route(limits.clone()))
+        .nest("/", public_route(limits))
         .with_state(pool)
         .fallback(handler_404)
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(middleware::from_fn(track_requests))
+        // Probes are added after the trace layer so they do not fill the logs
+        .merge(health_route(health))
+        .merge(docs_route());
+    let app = with_http_layers(app, cors);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .unwrap();
-    info!("listening on http://{}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    info!("listening on http://{}", listener.local_addr()?);
+    // On shutdown the server stops accepting connections and waits for the in-flight requests,
+    // then the outbox worker finishes the delivery it is running
+    // The client address is kept for the rate limits
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            info!("shutting down");
+            let _ = shutdown.send(true);
+        })
+        .await?;
+    if let Err(err) = worker.await {
+        tracing::error!("Outbox worker failed: {}", err);
+    }
+    Ok(())
+}
+
+// shutdown_signal resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
@@ -0,0 +1,60 @@
+use std::env;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use axum_extra::extract::CookieJar;
+
+use crate::{
+    schema::{session::Session, user::User},
+    storage::Storage,
+};
+
+use super::error::ApiError;
+
+// Name of the cookie holding the session token in the web UI
+pub const SESSION_COOKIE: &str = "session";
+
+// session_cookie_secure tells whether the session cookie is only sent over HTTPS
+// SESSION_COOKIE_SECURE=false lets the web UI be used over plain HTTP, for local development
+pub fn session_cookie_secure() -> bool {
+    env::var("SESSION_COOKIE_SECURE").map_or(true, |value| value != "false")
+}
+
+// CurrentUser is the user of the session the request carries, either as an
+// `Authorization: Bearer <token>` header or as the session cookie set by the web UI
+#[derive(Clone, Debug)]
+pub struct CurrentUser {
+    pub user: User,
+    pub token: String,
+}
+
+#[async_trait]
+impl FromRequestParts<Storage> for CurrentUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, pool: &Storage) -> Result<Self, Self::Rejection> {
+        let bearer = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let token = match bearer {
+            Some(token) => token,
+            None => CookieJar::from_headers(&parts.headers)
+                .get(SESSION_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+                .ok_or_else(|| ApiError::Unauthorized("Please log in".to_string()))?,
+        };
+        match Session::user(pool, &token).await {
+            Ok(user) => Ok(CurrentUser { user, token }),
+            Err(sqlx::Error::RowNotFound) => Err(ApiError::Unauthorized(
+                "Session expired, Please log in again".to_string(),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
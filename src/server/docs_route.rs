@@ -0,0 +1,80 @@
+use axum::Router;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use super::handler;
+
+// ApiDoc is the OpenAPI document of the HTTP API, generated from the handlers and their types
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Birthday Wisher", description = "Keep a list of friends and wish them on their birthday"),
+    paths(
+        handler::signup,
+        handler::login,
+        handler::verify_otp,
+        handler::logout,
+        handler::get_me,
+        handler::update_me,
+        handler::delete_me,
+        handler::change_email,
+        handler::verify_email_change,
+        handler::list_friends,
+        handler::show_friends,
+        handler::get_friend,
+        handler::remove_friend,
+        handler::add_friend,
+        handler::get_message_status,
+        handler::healthz,
+        handler::readyz,
+        handler::version,
+    ),
+    modifiers(&SessionAuth),
+    tags(
+        (name = "user", description = "Sign up and log in with an OTP sent by email"),
+        (name = "account", description = "Profile, email change and removal of the logged in user"),
+        (name = "friend", description = "Friends who get birthday wishes"),
+        (name = "outbox", description = "Delivery status of the queued emails"),
+        (name = "health", description = "Probes for the process supervisor"),
+    )
+)]
+pub struct ApiDoc;
+
+// SessionAuth documents the bearer token returned by /verifyOtp
+struct SessionAuth;
+
+impl Modify for SessionAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+// docs_route serves the document at /openapi.json and the Swagger UI page at /docs
+// The Swagger UI files are bundled in the binary
+pub fn docs_route() -> Router {
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+
+    #[test]
+    fn test_openapi_lists_every_route() {
+        let doc = ApiDoc::openapi();
+        for path in ["/signup", "/login", "/verifyOtp", "/logout", "/me", "/me/email", "/me/email/verify", "/friend", "/friend/{id}", "/outbox/{id}"] {
+            assert!(doc.paths.paths.contains_key(path), "{} is missing", path);
+        }
+        assert!(doc.components.unwrap().schemas.contains_key("ErrorBody"));
+    }
+}
@@ -1,65 +1,251 @@
-use axum::{extract::rejection::JsonRejection, response::IntoResponse, Json};
-use serde_json::json;
+use axum::{
+    extract::rejection::{FormRejection, JsonRejection, QueryRejection},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
 use thiserror::Error;
 
 use sqlx::Error as SqlxError;
+
+use super::layers::current_request_id;
+
 #[derive(Debug, Error)]
 pub enum ApiError {
     #[error(transparent)]
     JsonExtractionRejection(#[from] JsonRejection),
+    #[error(transparent)]
+    QueryExtractionRejection(#[from] QueryRejection),
+    #[error(transparent)]
+    FormExtractionRejection(#[from] FormRejection),
     #[error("{0}")]
     BadRequest(String),
-    #[error("")]
+    #[error("{0}")]
     NotFound(String),
-    #[error("Internal Server Error")]
-    InternalServerError,
+    #[error("{0}")]
+    Unauthorized(String),
+    // TooManyRequests holds the number of seconds to wait before trying again
+    #[error("Too many requests, Please try again in {0} seconds")]
+    TooManyRequests(u64),
     #[error("Failed to send Email")]
     EmailError,
     #[error("{0}")]
     TransactionError(String),
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error(transparent)]
+    Friend(#[from] FriendError),
+    #[error(transparent)]
+    User(#[from] UserError),
+    #[error(transparent)]
+    Database(#[from] SqlxError),
+}
+
+impl ApiError {
+    // code is a stable, machine-readable name of the error, sent next to the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::JsonExtractionRejection(_) => "invalid_json",
+            ApiError::QueryExtractionRejection(_) => "invalid_query",
+            ApiError::FormExtractionRejection(_) => "invalid_form",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::TooManyRequests(_) => "rate_limited",
+            ApiError::EmailError => "email_error",
+            ApiError::TransactionError(_) => "transaction_error",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Friend(FriendError::FriendNotFound) => "friend_not_found",
+            ApiError::Friend(FriendError::FriendAlreadyExist) => "friend_already_exists",
+            ApiError::User(UserError::UserNotFound) => "user_not_found",
+            ApiError::User(UserError::UserAlreadyExist) => "user_already_exists",
+            ApiError::User(UserError::UserNotVerified) => "user_not_verified",
+            ApiError::Friend(FriendError::SqlxError(_))
+            | ApiError::User(UserError::SqlxError(_))
+            | ApiError::Database(_) => "database_error",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ApiError::JsonExtractionRejection(json_rejection) => json_rejection.status(),
+            ApiError::QueryExtractionRejection(query_rejection) => query_rejection.status(),
+            ApiError::FormExtractionRejection(form_rejection) => form_rejection.status(),
+            ApiError::BadRequest(_)
+            | ApiError::Validation(_)
+            | ApiError::Friend(FriendError::FriendAlreadyExist)
+            | ApiError::User(UserError::UserAlreadyExist) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::User(UserError::UserNotVerified) => StatusCode::FORBIDDEN,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::NotFound(_)
+            | ApiError::Friend(FriendError::FriendNotFound)
+            | ApiError::User(UserError::UserNotFound) => StatusCode::NOT_FOUND,
+            ApiError::EmailError
+            | ApiError::TransactionError(_)
+            | ApiError::Friend(FriendError::SqlxError(_))
+            | ApiError::User(UserError::SqlxError(_))
+            | ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// FieldError is returned when a field of the request body is not valid
+// field is the name of the field as it appears in the JSON body
+#[derive(Debug, Error, Serialize, ToSchema)]
+#[error("{message}")]
+pub struct FieldError {
+    #[schema(value_type = String, example = "email")]
+    pub field: &'static str,
+    pub message: String,
+}
+
+// ValidationError lists every invalid field of a request body
+#[derive(Debug, Default, Error)]
+#[error("Invalid request body")]
+pub struct ValidationError {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationError {
+    // check records the field as invalid when its validator returned an error
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.errors.push(FieldError { field, message });
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl ApiError {
+    // message is what the client is told, database errors are logged and their detail is hidden
+    pub(crate) fn message(&self) -> String {
+        match self {
+            ApiError::JsonExtractionRejection(json_rejection) => json_rejection.body_text(),
+            ApiError::QueryExtractionRejection(query_rejection) => query_rejection.body_text(),
+            ApiError::FormExtractionRejection(form_rejection) => form_rejection.body_text(),
+            ApiError::Friend(FriendError::SqlxError(err))
+            | ApiError::User(UserError::SqlxError(err))
+            | ApiError::Database(err) => {
+                tracing::error!("Database error: {}", err);
+                "Internal Server Error".to_string()
+            }
+            err => err.to_string(),
+        }
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
-        let (status, message) = match self {
-            ApiError::JsonExtractionRejection(json_rejection) => {
-                (json_rejection.status(), json_rejection.body_text())
-            }
-            ApiError::BadRequest(message) => (axum::http::StatusCode::BAD_REQUEST, message),
-            ApiError::NotFound(message) => (axum::http::StatusCode::NOT_FOUND, message),
-            ApiError::InternalServerError => (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            ),
-            ApiError::EmailError => (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to send Email".to_string(),
-            ),
-            ApiError::TransactionError(message) => (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                message.to_string(),
-            ),
-        };
+        let status = self.status();
+        let code = self.code();
+        let message = self.message();
 
-        let payload = json!({
-            "message": message,
-            "status": status.as_u16(),
-        });
         tracing::error!("Error: {}", message);
-        (status, Json(payload)).into_response()
+        let retry_after = match self {
+            ApiError::TooManyRequests(seconds) => Some(seconds),
+            _ => None,
+        };
+        let errors = match self {
+            ApiError::Validation(error) => Some(error.errors),
+            _ => None,
+        };
+        let payload = ErrorBody {
+            code,
+            message,
+            status: status.as_u16(),
+            errors,
+            request_id: current_request_id(),
+        };
+        let mut response = (status, Json(payload)).into_response();
+        if let Some(seconds) = retry_after {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
     }
 }
 
+// ErrorBody is the JSON body of every error response
+// errors is only present when the request body did not pass validation
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(value_type = String, example = "friend_not_found")]
+    pub code: &'static str,
+    pub message: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+    // Id of the request, also in the x-request-id header, to find its logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
 // FriendError is enum type which is used to handle error
+#[derive(Debug, Error)]
 pub enum FriendError {
-    FriendNotFound, // FriendNotFound is used when friend is not found in the database table
+    // FriendNotFound is used when friend is not found in the database table
     // Ex: When we try to remove or get friend which is not in the list
-    FriendAlreadyExist, // FriendAlreadyExist is used when friend with provided email already exist in the database table
-    SqlxError(SqlxError), // SqlxError is used when sqlx crate return error
+    #[error("Friend Not Found with Given Id")]
+    FriendNotFound,
+    // FriendAlreadyExist is used when friend with provided email already exist in the database table
+    #[error("Friend Already Exist with given email id")]
+    FriendAlreadyExist,
+    // SqlxError is used when sqlx crate return error
+    #[error(transparent)]
+    SqlxError(SqlxError),
 }
 
+#[derive(Debug, Error)]
 pub enum UserError {
+    #[error("User Not Found with given email id")]
     UserNotFound,
+    #[error("User Already Exist with given email id")]
     UserAlreadyExist,
+    #[error("Email not verified yet, sign up again to get a new OTP")]
+    UserNotVerified,
+    #[error(transparent)]
     SqlxError(SqlxError),
 }
+
+// MailError is returned when an email could not be built or delivered
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("Invalid email address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("Failed to build email: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("Failed to render email: {0}")]
+    Template(#[from] askama::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Email task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Failed to queue email: {0}")]
+    Queue(#[from] SqlxError),
+    #[error("Email was not queued")]
+    NotQueued,
+    #[error("Please set up {0} in your environment")]
+    MissingEnv(&'static str),
+}
+
+// ServeError is returned when the server can not start or stops unexpectedly
+#[derive(Debug, Error)]
+pub enum ServeError {
+    #[error("Failed to set up the mailer: {0}")]
+    Mailer(#[from] MailError),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Failed to listen on {0}: {1}")]
+    Bind(String, std::io::Error),
+    #[error("Server error: {0}")]
+    Io(#[from] std::io::Error),
+}
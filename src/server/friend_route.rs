@@ -1,14 +1,14 @@
 use axum::{
-    routing::{get, post},
+    routing::get,
     Router,
 };
-use sqlx::{Pool, Postgres};
+use crate::storage::Storage;
 
-use super::handler::{add_friend, get_friend, remove_friend, show_friends};
+use super::handler::{add_friend, get_friend, list_friends, remove_friend, show_friends};
 
-pub fn friend_route() -> Router<Pool<Postgres>> {
+pub fn friend_route() -> Router<Storage> {
     Router::new()
         .route("/get_all", get(show_friends))
         .route("/:id", get(get_friend).delete(remove_friend))
-        .route("/", post(add_friend))
+        .route("/", get(list_friends).post(add_friend))
 }
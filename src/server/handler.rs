@@ -1,194 +1,815 @@
+use std::{future::Future, time::Duration};
+
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
     Json,
 };
 use axum_extra::extract::WithRejection;
+use chrono::{DateTime, Utc};
 
 use serde::Serialize;
-use sqlx::{Pool, Postgres};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+use crate::{
+    helper::metrics::{self, OTP_EXPIRED, OTP_INVALID, OTP_NOT_FOUND, OTP_VERIFIED},
+    storage::Storage,
+};
 
 use crate::schema::{
-    api::{EnteredOtp, LoginUser, NewUser}, friend::{Friend, NewFriend}, otps::Otp, user::User
+    api::{EnteredOtp, LoginUser, NewEmail, NewUser, UpdateUser}, friend::{Friend, FriendListQuery, FriendPage, NewFriend}, otps::{Otp, EMAIL_CHANGE, LOGIN, SIGNUP}, outbox::{MessageStatus, OutboxEntry}, session::Session, user::User
+};
+
+use super::{
+    auth::CurrentUser,
+    error::{ApiError, ErrorBody, UserError},
+    health_route::HealthState,
 };
 
-use super::error::{ApiError, FriendError, UserError};
+// How long each readiness check may take before it counts as failed
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, ToSchema)]
+pub struct Response {
+    status: u16,
+    message: String,
+}
 
-#[derive(Serialize)]
-struct Response {
+// OtpResponse is returned when an OTP email is queued
+// message_id can be used with /outbox/:id to follow its delivery
+#[derive(Serialize, ToSchema)]
+pub struct OtpResponse {
     status: u16,
     message: String,
+    message_id: i32,
 }
 
+#[utoipa::path(
+    post,
+    path = "/signup",
+    tag = "user",
+    request_body = NewUser,
+    responses(
+        (status = 200, description = "User created, or not verified yet, an OTP email is queued", body = OtpResponse),
+        (status = 400, description = "Invalid body or email already taken", body = ErrorBody),
+    )
+)]
 pub async fn signup(
-    State(pool): State<Pool<Postgres>>,
+    State(pool): State<Storage>,
     WithRejection(Json(user), _): WithRejection<Json<NewUser>, ApiError>,
-) -> impl IntoResponse {
-    let result = User::get_user_by_email(&pool, &user.email).await;
-    match result {
-        Ok(_) => Err(ApiError::BadRequest(
-            "User Already Exist with given email id".to_string(),
-        )),
-        Err(err) => match err {
-            UserError::UserNotFound => {
-                let result = user.add(&pool).await;
-                match result {
-                    Ok(user) => {
-                        user.send_otp("Signup".to_string(),&pool).await?;
-                        Ok(Json(Response {
-                        status: StatusCode::OK.as_u16(),
-                        message: "User Created".to_string(),
-                    }))},
-                    Err(_) => Err(ApiError::InternalServerError),
-                }
-            }
-            _ => Err(ApiError::InternalServerError),
+) -> Result<Json<OtpResponse>, ApiError> {
+    user.validate()?;
+    let user = match user.add(&pool).await {
+        Ok(user) => user,
+        // An account that was never verified signs up again to get a new OTP
+        Err(UserError::UserAlreadyExist) => match User::get_user_by_email(&pool, &user.email).await? {
+            existing if !existing.is_verified() => existing,
+            _ => return Err(UserError::UserAlreadyExist.into()),
         },
-    }
+        Err(err) => return Err(err.into()),
+    };
+    let message_id = user.send_otp(SIGNUP.to_string(), &pool).await?;
+    Ok(Json(OtpResponse {
+        status: StatusCode::OK.as_u16(),
+        message: "User Created".to_string(),
+        message_id,
+    }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "user",
+    request_body = LoginUser,
+    responses(
+        (status = 200, description = "An OTP email is queued", body = OtpResponse),
+        (status = 400, description = "Invalid body", body = ErrorBody),
+        (status = 403, description = "The email was never verified", body = ErrorBody),
+        (status = 404, description = "No user with this email", body = ErrorBody),
+    )
+)]
 pub async fn login(
-    State(pool): State<Pool<Postgres>>,
+    State(pool): State<Storage>,
     WithRejection(Json(user), _): WithRejection<Json<LoginUser>, ApiError>,
-) -> impl IntoResponse {
-    let result = User::get_user_by_email(&pool, &user.email).await;
-    match result {
-        Ok(user) => {
-            user.send_otp("Login".to_string(), &pool).await?;
-            Ok(Json(Response {
-            status: StatusCode::OK.as_u16(),
-            message: "User Found".to_string(),
-        }))},
-        Err(err) => match err {
-            UserError::UserNotFound => Err(ApiError::NotFound(
-                "User Not Found with given email id".to_string(),
-            )),
-            _ => Err(ApiError::InternalServerError),
-        },
-    }
+) -> Result<Json<OtpResponse>, ApiError> {
+    user.validate()?;
+    let user = User::get_user_for_login(&pool, &user.email).await?;
+    let message_id = user.send_otp(LOGIN.to_string(), &pool).await?;
+    Ok(Json(OtpResponse {
+        status: StatusCode::OK.as_u16(),
+        message: "User Found".to_string(),
+        message_id,
+    }))
 }
 
+// SessionResponse is returned when an OTP is verified
+// token is sent as `Authorization: Bearer <token>` until expires_at
+#[derive(Serialize, ToSchema)]
+pub struct SessionResponse {
+    status: u16,
+    message: String,
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/verifyOtp",
+    tag = "user",
+    request_body = EnteredOtp,
+    responses(
+        (status = 200, description = "OTP verified, it can not be used again. A session is opened", body = SessionResponse),
+        (status = 400, description = "Invalid body, wrong or expired OTP", body = ErrorBody),
+        (status = 404, description = "No OTP was sent to this email", body = ErrorBody),
+    )
+)]
 pub async fn verify_otp(
-    State(pool): State<Pool<Postgres>>,
+    State(pool): State<Storage>,
     WithRejection(Json(entered_otp), _): WithRejection<Json<EnteredOtp>, ApiError>,
-) -> impl IntoResponse {
-    let result = Otp::get_otp(entered_otp.email, &pool).await;
-    match result {
-        Ok(mut otp) =>  {
-            let result = otp.verify_otp(entered_otp.otp).await;
-            if result {
-                match otp.otp_used(&pool).await {
-                    Ok(_) => {
-                        Ok(Json(Response {
-                            status: StatusCode::OK.as_u16(),
-                            message: "OTP Verified".to_string(),
-                        }))
-                    },
-                    Err(_) => Err(ApiError::InternalServerError),
-                }
-            }
-            else{
-                return Err(ApiError::BadRequest(
-                    "Invalid OTP".to_string(),
-                ))
-            }
-        },
-        Err(err) =>{
-            match err {
-                sqlx::Error::RowNotFound => Err(ApiError::NotFound(
-                    "OTP Not Found with given email id".to_string(),
-                )),
-                _ => Err(ApiError::InternalServerError),
-            }
-        },
+) -> Result<Json<SessionResponse>, ApiError> {
+    let user = confirm_otp(&pool, entered_otp).await?;
+    let session = Session::start(&pool, &user).await?;
+    Ok(Json(SessionResponse {
+        status: StatusCode::OK.as_u16(),
+        message: "OTP Verified".to_string(),
+        token: session.token,
+        expires_at: session.expires_at,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "user",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The session is closed, its token can not be used again", body = Response),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+    )
+)]
+pub async fn logout(
+    State(pool): State<Storage>,
+    current: CurrentUser,
+) -> Result<Json<Response>, ApiError> {
+    Session::end(&pool, &current.token).await?;
+    Ok(Json(Response {
+        status: StatusCode::OK.as_u16(),
+        message: "Logged Out".to_string(),
+    }))
+}
+
+// confirm_otp checks a signup or login OTP and uses it up, it returns the user it was sent to
+// The API and the web UI both log in through it
+pub async fn confirm_otp(pool: &Storage, entered_otp: EnteredOtp) -> Result<User, ApiError> {
+    let otp = check_otp(pool, entered_otp, &[SIGNUP, LOGIN], None).await?;
+    let user = User::get_user_by_email(pool, &otp.email).await?;
+    if otp.created_for == SIGNUP {
+        return Ok(user.verify(pool).await?);
+    }
+    Ok(user)
+}
+
+// check_otp checks the last OTP sent to the email and uses it up
+// An OTP sent for another purpose is refused like a wrong one, wrong codes count towards OTP_MAX_ATTEMPTS
+// An expired OTP, or one guessed wrong too many times, is deleted and a new one has to be asked for
+// With a user_id only an OTP sent for that account is found, another account can not use it or spend its tries
+async fn check_otp(
+    pool: &Storage,
+    entered_otp: EnteredOtp,
+    purposes: &[&str],
+    user_id: Option<i32>,
+) -> Result<Otp, ApiError> {
+    entered_otp.validate()?;
+    let otp = Otp::get_otp(entered_otp.email, pool)
+        .await
+        .and_then(|otp| match user_id {
+            Some(user_id) if otp.user_id != user_id => Err(sqlx::Error::RowNotFound),
+            _ => Ok(otp),
+        });
+    let mut otp = match otp {
+        Ok(otp) => otp,
+        Err(sqlx::Error::RowNotFound) => {
+            metrics::otp_verification(OTP_NOT_FOUND);
+            return Err(ApiError::NotFound(
+                "OTP Not Found with given email id".to_string(),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if otp.is_spent() {
+        otp.otp_used(pool).await?;
+        metrics::otp_verification(OTP_EXPIRED);
+        return Err(ApiError::BadRequest("OTP expired, please ask for a new one".to_string()));
+    }
+    if !purposes.contains(&otp.created_for.as_str()) || !otp.verify_otp(entered_otp.otp).await {
+        otp.wrong_attempt(pool).await?;
+        metrics::otp_verification(OTP_INVALID);
+        return Err(ApiError::BadRequest("Invalid OTP".to_string()));
     }
+    otp.otp_used(pool).await?;
+    metrics::otp_verification(OTP_VERIFIED);
+    Ok(otp)
+}
+
+#[utoipa::path(
+    get,
+    path = "/me",
+    tag = "account",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The logged in user", body = User),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+    )
+)]
+pub async fn get_me(current: CurrentUser) -> Json<User> {
+    Json(current.user)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/me",
+    tag = "account",
+    security(("session" = [])),
+    request_body = UpdateUser,
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 400, description = "Invalid body", body = ErrorBody),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+    )
+)]
+pub async fn update_me(
+    State(pool): State<Storage>,
+    current: CurrentUser,
+    WithRejection(Json(update), _): WithRejection<Json<UpdateUser>, ApiError>,
+) -> Result<Json<User>, ApiError> {
+    update.validate()?;
+    let user = current.user.update(&pool, &update).await?;
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/me",
+    tag = "account",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The account, the friends added with it, its OTPs and sent emails are removed", body = Response),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+    )
+)]
+pub async fn delete_me(
+    State(pool): State<Storage>,
+    current: CurrentUser,
+) -> Result<Json<Response>, ApiError> {
+    current.user.delete(&pool).await?;
+    Ok(Json(Response {
+        status: StatusCode::OK.as_u16(),
+        message: "Account Deleted".to_string(),
+    }))
 }
 
-pub async fn show_friends(State(pool): State<Pool<Postgres>>) -> Json<Vec<Friend>> {
-    let friends = Friend::get_friends(&pool).await.unwrap();
-    Json(friends)
+// change_email sends an OTP to the new address, the email changes once it is confirmed
+#[utoipa::path(
+    post,
+    path = "/me/email",
+    tag = "account",
+    security(("session" = [])),
+    request_body = NewEmail,
+    responses(
+        (status = 200, description = "An OTP email is queued to the new address", body = OtpResponse),
+        (status = 400, description = "Invalid body, or the email is already used", body = ErrorBody),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+    )
+)]
+pub async fn change_email(
+    State(pool): State<Storage>,
+    current: CurrentUser,
+    WithRejection(Json(new_email), _): WithRejection<Json<NewEmail>, ApiError>,
+) -> Result<Json<OtpResponse>, ApiError> {
+    new_email.validate()?;
+    if new_email.email == current.user.email {
+        return Err(ApiError::BadRequest("This is already your email".to_string()));
+    }
+    match User::get_user_by_email(&pool, &new_email.email).await {
+        Ok(_) => return Err(UserError::UserAlreadyExist.into()),
+        Err(UserError::UserNotFound) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let user = User { email: new_email.email, ..current.user };
+    let message_id = user.send_otp(EMAIL_CHANGE.to_string(), &pool).await?;
+    Ok(Json(OtpResponse {
+        status: StatusCode::OK.as_u16(),
+        message: "OTP Sent".to_string(),
+        message_id,
+    }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/me/email/verify",
+    tag = "account",
+    security(("session" = [])),
+    request_body = EnteredOtp,
+    responses(
+        (status = 200, description = "The email is changed", body = User),
+        (status = 400, description = "Invalid body, wrong or expired OTP, or the email was taken meanwhile", body = ErrorBody),
+        (status = 401, description = "No session or the session expired", body = ErrorBody),
+        (status = 404, description = "No OTP was sent to this email for this account", body = ErrorBody),
+    )
+)]
+pub async fn verify_email_change(
+    State(pool): State<Storage>,
+    current: CurrentUser,
+    WithRejection(Json(entered_otp), _): WithRejection<Json<EnteredOtp>, ApiError>,
+) -> Result<Json<User>, ApiError> {
+    let otp = check_otp(&pool, entered_otp, &[EMAIL_CHANGE], Some(current.user.id)).await?;
+    let user = current.user.change_email(&pool, &otp.email).await?;
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    get,
+    path = "/friend",
+    tag = "friend",
+    params(FriendListQuery),
+    responses(
+        (status = 200, description = "One page of the friends matching the search", body = FriendPage),
+        (status = 400, description = "Invalid limit, cursor or sort", body = ErrorBody),
+    )
+)]
+pub async fn list_friends(
+    State(pool): State<Storage>,
+    WithRejection(Query(query), _): WithRejection<Query<FriendListQuery>, ApiError>,
+) -> Result<Json<FriendPage>, ApiError> {
+    let (limit, after) = query.page()?;
+    let page = Friend::search(&pool, None, query.search(), query.sort, limit, after).await?;
+    Ok(Json(page))
+}
+
+#[utoipa::path(
+    get,
+    path = "/friend/get_all",
+    tag = "friend",
+    responses((status = 200, description = "Every friend, unsorted. Prefer GET /friend", body = Vec<Friend>))
+)]
+pub async fn show_friends(State(pool): State<Storage>) -> Result<Json<Vec<Friend>>, ApiError> {
+    let friends = Friend::get_friends(&pool).await?;
+    Ok(Json(friends))
+}
+
+#[utoipa::path(
+    get,
+    path = "/friend/{id}",
+    tag = "friend",
+    params(("id" = i32, Path, description = "Id of the friend")),
+    responses(
+        (status = 200, description = "The friend", body = Friend),
+        (status = 404, description = "No friend with this id", body = ErrorBody),
+    )
+)]
 pub async fn get_friend(
     Path(id): Path<i32>,
-    State(pool): State<Pool<Postgres>>,
-) -> Result<Json<Friend>, impl IntoResponse> {
-    let friend = Friend::get_friend(&pool, id).await;
-    match friend {
-        Ok(friend) => Ok(Json(friend)),
-        Err(_) => Err((
-            StatusCode::NOT_FOUND,
-            Json(Response {
-                status: StatusCode::NOT_FOUND.as_u16(),
-                message: "Friend Not Found with Given Id".to_string(),
-            }),
-        )),
-    }
+    State(pool): State<Storage>,
+) -> Result<Json<Friend>, ApiError> {
+    let friend = Friend::get_friend(&pool, id).await?;
+    Ok(Json(friend))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/friend/{id}",
+    tag = "friend",
+    params(("id" = i32, Path, description = "Id of the friend")),
+    responses(
+        (status = 200, description = "The removed friend", body = Friend),
+        (status = 404, description = "No friend with this id", body = ErrorBody),
+    )
+)]
 pub async fn remove_friend(
     Path(id): Path<i32>,
-    State(pool): State<Pool<Postgres>>,
-) -> Result<Json<Friend>, impl IntoResponse> {
-    let friend = Friend::get_friend(&pool, id).await;
-    match friend {
-        Ok(friend) => {
-            let friend = friend.remove_friend(&pool).await;
-            match friend {
-                Ok(friend) => Ok(Json(friend)),
-                Err(_) => Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(Response {
-                        status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-                        message: "Something went wrong".to_string(),
-                    }),
-                )),
-            }
-        }
-        Err(_) => Err((
-            StatusCode::NOT_FOUND,
-            Json(Response {
-                status: StatusCode::NOT_FOUND.as_u16(),
-                message: "Friend Not Found with Given Id".to_string(),
-            }),
-        )),
-    }
+    State(pool): State<Storage>,
+) -> Result<Json<Friend>, ApiError> {
+    let friend = Friend::get_friend(&pool, id).await?;
+    let friend = friend.remove_friend(&pool).await?;
+    Ok(Json(friend))
 }
 
+#[utoipa::path(
+    post,
+    path = "/friend",
+    tag = "friend",
+    // The session is optional, a friend added with one is removed along with the account
+    security((), ("session" = [])),
+    request_body = NewFriend,
+    responses(
+        (status = 200, description = "The added friend", body = Friend),
+        (status = 400, description = "Invalid body or email already in the list", body = ErrorBody),
+    )
+)]
 pub async fn add_friend(
-    State(pool): State<Pool<Postgres>>,
-    Json(friend): Json<NewFriend>,
-) -> Result<Json<Friend>, impl IntoResponse> {
-    let result = friend.add(&pool).await;
-    match result {
-        Ok(friend) => Ok(Json(friend)),
-        Err(err) => match err {
-            FriendError::FriendAlreadyExist => Err((
-                StatusCode::BAD_REQUEST,
-                Json(Response {
-                    status: StatusCode::BAD_REQUEST.as_u16(),
-                    message: "Friend Already Exist with given email id".to_string(),
-                }),
-            )),
-
-            _ => Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(Response {
-                    status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-                    message: "Something went wrong".to_string(),
-                }),
-            )),
-        },
+    State(pool): State<Storage>,
+    current: Option<CurrentUser>,
+    WithRejection(Json(friend), _): WithRejection<Json<NewFriend>, ApiError>,
+) -> Result<Json<Friend>, ApiError> {
+    friend.validate()?;
+    // With a session the friend belongs to the user and is removed with their account
+    let owner = current.map(|current| current.user);
+    let friend = friend.add(&pool, owner.as_ref()).await?;
+    Ok(Json(friend))
+}
+
+#[utoipa::path(
+    get,
+    path = "/outbox/{id}",
+    tag = "outbox",
+    params(("id" = i32, Path, description = "message_id returned when the email was queued")),
+    responses(
+        (status = 200, description = "Delivery status of the message", body = MessageStatus),
+        (status = 404, description = "No message with this id", body = ErrorBody),
+    )
+)]
+pub async fn get_message_status(
+    Path(id): Path<i32>,
+    State(pool): State<Storage>,
+) -> Result<Json<MessageStatus>, ApiError> {
+    match OutboxEntry::get_status(&pool, id).await {
+        Ok(status) => Ok(Json(status)),
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::NotFound(
+            "Message Not Found with Given Id".to_string(),
+        )),
+        Err(err) => Err(err.into()),
     }
 }
 
-pub async fn handler_404() -> impl IntoResponse {
-    (
-        StatusCode::NOT_FOUND,
-        Json(Response {
-            status: StatusCode::NOT_FOUND.as_u16(),
-            message: "Not Found".to_string(),
-        }),
+// healthz answers as long as the process is running
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The process is running"))
+)]
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// readyz checks everything a request may need: the database, its migrations and the SMTP server
+// It returns 503 with the failing checks when one of them is not available
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The database, its migrations and the SMTP server are available"),
+        (status = 503, description = "At least one check failed, the body lists them"),
     )
+)]
+pub async fn readyz(State(state): State<HealthState>) -> (StatusCode, Json<Value>) {
+    let database = ready_check(async { state.pool.ping().await.map_err(|err| err.to_string()) });
+    let migrations = ready_check(async {
+        match state.pool.is_up_to_date().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("pending migrations".to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    });
+    let mail = ready_check(async {
+        match state.mailer.test_connection().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("SMTP server refused the connection".to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    });
+    let (database, migrations, mail) = tokio::join!(database, migrations, mail);
+
+    let checks = [("database", database), ("migrations", migrations), ("mail", mail)];
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: serde_json::Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, result)| (name.to_string(), json!(result.err().unwrap_or_else(|| "ok".to_string()))))
+        .collect();
+    let (status, message) = if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    };
+    (status, Json(json!({ "status": message, "checks": checks })))
+}
+
+async fn ready_check(check: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+    match tokio::time::timeout(READY_CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+// export_metrics returns every metric in the Prometheus text format
+pub async fn export_metrics(State(state): State<HealthState>) -> String {
+    metrics::render(&state.pool)
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "health",
+    responses((status = 200, description = "Name and version of the server"))
+)]
+pub async fn version() -> Json<Value> {
+    Json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+pub async fn handler_404() -> ApiError {
+    ApiError::NotFound("Not Found".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+
+    use axum::{
+        extract::{Path, Query, State},
+        http::StatusCode,
+        response::IntoResponse,
+        Json,
+    };
+    use axum_extra::extract::WithRejection;
+    use chrono::{Duration, Local, NaiveDate, Utc};
+
+    use crate::{
+        helper::locale::Locale,
+        schema::{
+            api::{EnteredOtp, LoginUser, NewEmail, NewUser, UpdateUser},
+            friend::{FriendListQuery, FriendPage, FriendSort, NewFriend},
+            otps::{Otp, SIGNUP},
+            session::Session,
+        },
+        storage::{memory::MemoryStore, OtpRepository, OutboxRepository, Storage, UserRepository},
+    };
+
+    use super::{
+        add_friend, change_email, delete_me, get_friend, list_friends, login, remove_friend, signup, update_me,
+        verify_email_change, verify_otp, CurrentUser,
+    };
+
+    fn with_json<T>(value: T) -> WithRejection<Json<T>, super::ApiError> {
+        WithRejection(Json(value), PhantomData)
+    }
+
+    fn entered_otp(otp: &str) -> WithRejection<Json<EnteredOtp>, super::ApiError> {
+        with_json(EnteredOtp {
+            email: "asha@example.com".to_string(),
+            otp: otp.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_signup_and_verify_otp() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let user = NewUser {
+            name: "Asha".to_string(),
+            email: "asha@example.com".to_string(),
+            locale: "en".to_string(),
+        };
+
+        let login_user = || with_json(LoginUser { email: "asha@example.com".to_string() });
+        let long_locale = NewUser { locale: "x".repeat(50), ..user.clone() };
+        let response = signup(State(conn.clone()), with_json(long_locale)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        let response = signup(State(conn.clone()), with_json(user.clone())).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        // Until the email is verified the account can not log in, signing up again sends a new OTP
+        let response = login(State(conn.clone()), login_user()).await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        let response = signup(State(conn.clone()), with_json(user.clone())).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+
+        let otp = conn.get_otp("asha@example.com").await.unwrap().otp;
+        let response = verify_otp(State(conn.clone()), entered_otp("wrong")).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        let Json(session) = verify_otp(State(conn.clone()), entered_otp(&otp)).await.unwrap();
+        let verified = Session::user(&conn, &session.token).await.unwrap();
+        assert_eq!(verified.email, "asha@example.com");
+        assert!(verified.is_verified());
+        let response = signup(State(conn.clone()), with_json(user)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        // An OTP can only be used once, the one of the first signup is left and does not match
+        let response = verify_otp(State(conn.clone()), entered_otp(&otp)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        let response = login(State(conn), login_user()).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_otp_attempts_and_expiry() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let user = NewUser {
+            name: "Asha".to_string(),
+            email: "asha@example.com".to_string(),
+            locale: "en".to_string(),
+        };
+        let response = signup(State(conn.clone()), with_json(user)).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+
+        // The OTP is deleted after OTP_MAX_ATTEMPTS (5) wrong codes, the right one is refused then
+        let otp = conn.get_otp("asha@example.com").await.unwrap();
+        assert_eq!(otp.otp.len(), 6);
+        for attempt in 1..=5 {
+            let response = verify_otp(State(conn.clone()), entered_otp("000000")).await;
+            assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+            if attempt < 5 {
+                assert_eq!(conn.get_otp("asha@example.com").await.unwrap().attempts, attempt);
+            }
+        }
+        assert!(conn.get_otp("asha@example.com").await.is_err());
+        let response = verify_otp(State(conn.clone()), entered_otp(&otp.otp)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+
+        // An expired OTP is refused and deleted
+        let user = conn.get_user_by_email("asha@example.com").await.unwrap();
+        let mut expired = Otp::generate(user.id, "asha@example.com".to_string(), SIGNUP.to_string());
+        expired.expires_at = Utc::now() - Duration::minutes(1);
+        let code = expired.otp.clone();
+        expired.send_otp(Locale::En, &conn).await.unwrap();
+        let response = verify_otp(State(conn.clone()), entered_otp(&code)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        assert!(conn.get_otp("asha@example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_and_get_friend() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let friend = NewFriend {
+            name: "Ravi".to_string(),
+            email: "ravi@example.com".to_string(),
+            dob: NaiveDate::from_ymd_opt(1990, 8, 5).unwrap(),
+            locale: "en".to_string(),
+        };
+
+        let response = add_friend(State(conn.clone()), None, with_json(friend.clone())).await;
+        let id = match response {
+            Ok(Json(friend)) => friend.id,
+            Err(err) => panic!("Friend was not added: {}", err),
+        };
+        let err = add_friend(State(conn.clone()), None, with_json(friend.clone())).await.unwrap_err();
+        assert_eq!(err.code(), "friend_already_exists");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        let shouting = NewFriend { email: friend.email.to_uppercase(), ..friend.clone() };
+        let response = add_friend(State(conn.clone()), None, with_json(shouting)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        // Every invalid field is reported at once
+        let invalid = NewFriend {
+            name: " ".to_string(),
+            email: "ravi@localhost".to_string(),
+            dob: Local::now().date_naive() + Duration::days(1),
+            locale: "x".repeat(50),
+        };
+        let response = add_friend(State(conn.clone()), None, with_json(invalid)).await.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let fields: Vec<&str> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, ["name", "email", "dob", "locale"]);
+        assert_eq!(body["code"], "validation_failed");
+
+        let response = get_friend(Path(id), State(conn.clone())).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        let response = get_friend(Path(id + 1), State(conn)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_account() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let user = NewUser {
+            name: "Asha".to_string(),
+            email: "asha@example.com".to_string(),
+            locale: "en".to_string(),
+        };
+        let _ = signup(State(conn.clone()), with_json(user)).await.unwrap();
+        let otp = conn.get_otp("asha@example.com").await.unwrap().otp;
+        let Json(session) = verify_otp(State(conn.clone()), entered_otp(&otp)).await.unwrap();
+        let current = || async {
+            let user = Session::user(&conn, &session.token).await.unwrap();
+            CurrentUser { user, token: session.token.clone() }
+        };
+
+        let update = UpdateUser { name: Some("Asha R".to_string()), locale: None };
+        let Json(user) = update_me(State(conn.clone()), current().await, with_json(update)).await.unwrap();
+        assert_eq!((user.name.as_str(), user.locale.as_str()), ("Asha R", "en"));
+        let update = UpdateUser { name: None, locale: Some(" ".to_string()) };
+        let response = update_me(State(conn.clone()), current().await, with_json(update)).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+
+        // A login OTP is left under the old address
+        let login_user = with_json(LoginUser { email: "asha@example.com".to_string() });
+        let Json(old_address) = login(State(conn.clone()), login_user).await.unwrap();
+
+        let new_email = |email: &str| with_json(NewEmail { email: email.to_string() });
+        let response = change_email(State(conn.clone()), current().await, new_email("asha@example.com")).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        let _ = change_email(State(conn.clone()), current().await, new_email("asha@example.org")).await.unwrap();
+        let otp = conn.get_otp("asha@example.org").await.unwrap().otp;
+        let entered = with_json(EnteredOtp { email: "asha@example.org".to_string(), otp });
+        let Json(user) = verify_email_change(State(conn.clone()), current().await, entered).await.unwrap();
+        assert_eq!(user.email, "asha@example.org");
+        // An email change OTP does not log in, this change is never confirmed
+        let Json(unconfirmed) =
+            change_email(State(conn.clone()), current().await, new_email("asha@example.net")).await.unwrap();
+        let otp = conn.get_otp("asha@example.net").await.unwrap().otp;
+        // Another account can not confirm the change with the code, nor spend its tries
+        let kim = NewUser { name: "Kim".to_string(), email: "kim@example.com".to_string(), locale: "en".to_string() };
+        let kim = conn.insert_user(&kim).await.unwrap();
+        let kim_session = Session::start(&conn, &kim).await.unwrap();
+        let kim = CurrentUser { user: kim, token: kim_session.token };
+        let entered = with_json(EnteredOtp { email: "asha@example.net".to_string(), otp: otp.clone() });
+        let response = verify_email_change(State(conn.clone()), kim, entered).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        assert!(conn.get_user_by_email("kim@example.com").await.is_ok());
+        assert_eq!(conn.get_otp("asha@example.net").await.unwrap().attempts, 0);
+        let entered = with_json(EnteredOtp { email: "asha@example.net".to_string(), otp });
+        let response = verify_otp(State(conn.clone()), entered).await;
+        assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+
+        // Deleting the account removes the friends added with it, not the others
+        let friend = |email: &str| NewFriend {
+            name: "Ravi".to_string(),
+            email: email.to_string(),
+            dob: NaiveDate::from_ymd_opt(1990, 8, 5).unwrap(),
+            locale: "en".to_string(),
+        };
+        let Json(owned) = add_friend(State(conn.clone()), Some(current().await), with_json(friend("ravi@example.com")))
+            .await
+            .unwrap();
+        let Json(kept) = add_friend(State(conn.clone()), None, with_json(friend("ravi@example.org")))
+            .await
+            .unwrap();
+        let _ = delete_me(State(conn.clone()), current().await).await.unwrap();
+        assert!(conn.get_user_by_email("asha@example.org").await.is_err());
+        // The OTPs and their emails go too, whichever address they were sent to
+        for email in ["asha@example.com", "asha@example.org", "asha@example.net"] {
+            assert!(conn.get_otp(email).await.is_err());
+        }
+        assert!(conn.get_status(old_address.message_id).await.is_err());
+        assert!(conn.get_status(unconfirmed.message_id).await.is_err());
+        assert!(Session::user(&conn, &session.token).await.is_err());
+        let response = get_friend(Path(owned.id), State(conn.clone())).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        let response = get_friend(Path(kept.id), State(conn)).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_list_friends_pages() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let today = Local::now().date_naive();
+        let friends = [
+            ("Zoya", "zoya@example.com", today - Duration::days(400)),
+            ("asha", "asha@example.com", today - Duration::days(300)),
+            ("Mira", "mira@work.example", today - Duration::days(365 * 20)),
+        ];
+        for (name, email, dob) in friends {
+            let friend = NewFriend { name: name.to_string(), email: email.to_string(), dob, locale: "en".to_string() };
+            let _ = add_friend(State(conn.clone()), None, with_json(friend)).await.unwrap();
+        }
+        let list = |query: FriendListQuery| list_friends(State(conn.clone()), WithRejection(Query(query), PhantomData));
+        let names = |page: &FriendPage| page.friends.iter().map(|friend| friend.name.clone()).collect::<Vec<_>>();
+
+        let query = FriendListQuery { limit: Some(2), sort: FriendSort::Name, ..Default::default() };
+        let Json(page) = list(query).await.unwrap();
+        assert_eq!(names(&page), ["asha", "Mira"]);
+        assert_eq!(page.total, 3);
+        let cursor = page.next_cursor.clone();
+        // Friends added or removed before the cursor do not shift the next page
+        let asha = page.friends[0].id;
+        let _ = remove_friend(Path(asha), State(conn.clone())).await.unwrap();
+        let bob = NewFriend { name: "Bob".to_string(), email: "bob@example.com".to_string(), dob: today - Duration::days(1000), locale: "en".to_string() };
+        let _ = add_friend(State(conn.clone()), None, with_json(bob)).await.unwrap();
+        let query = FriendListQuery { limit: Some(2), cursor: cursor.clone(), sort: FriendSort::Name, ..Default::default() };
+        let Json(page) = list(query).await.unwrap();
+        assert_eq!(names(&page), ["Zoya"]);
+        assert_eq!(page.next_cursor, None);
+        // A cursor only continues the sort it was made for
+        let query = FriendListQuery { cursor, sort: FriendSort::Created, ..Default::default() };
+        assert_eq!(list(query).await.unwrap_err().code(), "validation_failed");
+
+        // Mira's birthday is today or within a few days, Asha's is about two months away
+        let query = FriendListQuery { sort: FriendSort::NextBirthday, ..Default::default() };
+        let Json(page) = list(query).await.unwrap();
+        assert_eq!(names(&page)[0], "Mira");
+
+        let query = FriendListQuery { q: Some(" WORK ".to_string()), ..Default::default() };
+        let Json(page) = list(query).await.unwrap();
+        assert_eq!((names(&page), page.total), (vec!["Mira".to_string()], 1));
+
+        let query = FriendListQuery { limit: Some(0), cursor: Some("x".to_string()), ..Default::default() };
+        let err = list(query).await.unwrap_err();
+        assert_eq!(err.code(), "validation_failed");
+    }
 }
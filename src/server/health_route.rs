@@ -0,0 +1,21 @@
+use axum::{routing::get, Router};
+
+use crate::{helper::mailer::Mailer, storage::Storage};
+
+use super::handler::{export_metrics, healthz, readyz, version};
+
+// HealthState is what the readiness check and the metrics need to reach
+#[derive(Clone)]
+pub struct HealthState {
+    pub pool: Storage,
+    pub mailer: Mailer,
+}
+
+pub fn health_route(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+        .route("/metrics", get(export_metrics))
+        .with_state(state)
+}
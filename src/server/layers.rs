@@ -0,0 +1,193 @@
+use std::{env, time::Duration};
+
+use axum::{
+    extract::Request,
+    http::{
+        header::{
+            HeaderName, AUTHORIZATION, CONTENT_TYPE, REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS,
+            X_FRAME_OPTIONS,
+        },
+        HeaderMap, HeaderValue, Method,
+    },
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+};
+use tracing::Span;
+
+use super::error::ServeError;
+
+// Headers added to every response, unless the handler already set them
+const SECURITY_HEADERS: [(HeaderName, &str); 3] = [
+    (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (X_FRAME_OPTIONS, "DENY"),
+    (REFERRER_POLICY, "no-referrer"),
+];
+
+// Header carrying the request id, a client may send its own
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    // REQUEST_ID is the id of the request being handled, error bodies repeat it
+    static REQUEST_ID: String;
+}
+
+// CorsConfig decides which other origins may call the API from a browser
+// CORS_ALLOWED_ORIGINS: comma separated origins (ex: `https://app.example.com`), `*` for any, none when unset
+// CORS_ALLOWED_METHODS: comma separated methods (default GET,POST,PATCH,DELETE)
+// CORS_ALLOW_CREDENTIALS: `true` lets the browser send cookies along (default false)
+#[derive(Debug, Default)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub credentials: bool,
+}
+
+impl CorsConfig {
+    pub fn from_env() -> CorsConfig {
+        let list = |name: &str| {
+            env::var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        CorsConfig {
+            origins: list("CORS_ALLOWED_ORIGINS"),
+            methods: list("CORS_ALLOWED_METHODS"),
+            credentials: env::var("CORS_ALLOW_CREDENTIALS").is_ok_and(|value| value == "true"),
+        }
+    }
+
+    // layer builds the CORS layer, a value that is not a valid origin or method stops the server
+    pub fn layer(&self) -> Result<CorsLayer, ServeError> {
+        let origins = if self.origins.iter().any(|origin| origin == "*") {
+            // Browsers refuse credentials from an API that allows any origin
+            if self.credentials {
+                return Err(ServeError::Config(
+                    "CORS_ALLOW_CREDENTIALS can not be used with CORS_ALLOWED_ORIGINS=*".to_string(),
+                ));
+            }
+            AllowOrigin::any()
+        } else {
+            let origins = self.origins.iter().map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|_| ServeError::Config(format!("Invalid CORS origin: {}", origin)))
+            });
+            AllowOrigin::list(origins.collect::<Result<Vec<_>, _>>()?)
+        };
+        let methods = if self.methods.is_empty() {
+            vec![Method::GET, Method::POST, Method::PATCH, Method::DELETE]
+        } else {
+            let methods = self.methods.iter().map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| ServeError::Config(format!("Invalid CORS method: {}", method)))
+            });
+            methods.collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE, HeaderName::from_static(REQUEST_ID_HEADER)])
+            .expose_headers([HeaderName::from_static(REQUEST_ID_HEADER)])
+            .allow_credentials(self.credentials)
+            .max_age(Duration::from_secs(3600)))
+    }
+}
+
+// MAX_BODY_BYTES is the largest request body the server reads (default 64 KiB)
+// A bigger body is refused with 413 before it reaches a handler
+pub fn max_body_bytes() -> usize {
+    env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(64 * 1024)
+}
+
+// request_id returns the id the request was given by SetRequestIdLayer
+pub fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok())
+}
+
+// current_request_id is the id of the request the task is handling, None outside of a request
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+// request_span is the span of a request, every log line written while handling it carries the id
+pub fn request_span(request: &Request) -> Span {
+    tracing::info_span!(
+        "request",
+        id = request_id(request.headers()).unwrap_or_default(),
+        method = %request.method(),
+        uri = %request.uri(),
+    )
+}
+
+async fn scope_request_id(request: Request, next: Next) -> Response {
+    match request_id(request.headers()).map(str::to_string) {
+        Some(id) => REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
+
+// with_http_layers wraps the whole app: CORS answers the preflight requests first,
+// then the request gets its id (sent back in x-request-id), then the body limit,
+// and every response gets the security headers
+pub fn with_http_layers(app: Router, cors: CorsLayer) -> Router {
+    let app = app.layer(RequestBodyLimitLayer::new(max_body_bytes()));
+    let app = SECURITY_HEADERS.into_iter().fold(app, |app, (name, value)| {
+        app.layer(SetResponseHeaderLayer::if_not_present(name, HeaderValue::from_static(value)))
+    });
+    let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
+    app.layer(middleware::from_fn(scope_request_id))
+        .layer(PropagateRequestIdLayer::new(request_id.clone()))
+        .layer(SetRequestIdLayer::new(request_id, MakeRequestUuid))
+        .layer(cors)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use crate::server::error::ApiError;
+
+    use super::{CorsConfig, REQUEST_ID};
+
+    #[test]
+    fn test_cors_config() {
+        let config = |origins: &[&str], methods: &[&str], credentials| CorsConfig {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: methods.iter().map(|method| method.to_string()).collect(),
+            credentials,
+        };
+        assert!(config(&[], &[], false).layer().is_ok());
+        assert!(config(&["https://app.example.com"], &["get", "post"], true).layer().is_ok());
+        assert!(config(&["*"], &[], false).layer().is_ok());
+        assert!(config(&["*"], &[], true).layer().is_err());
+        assert!(config(&["https://app.example.com\n"], &[], false).layer().is_err());
+        assert!(config(&[], &["GET POST"], false).layer().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_error_body_has_request_id() {
+        let error = || ApiError::NotFound("Not Found".to_string()).into_response();
+        let response = REQUEST_ID.scope("abc-123".to_string(), async { error() }).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], "abc-123");
+
+        let body = axum::body::to_bytes(error().into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("request_id").is_none());
+    }
+}
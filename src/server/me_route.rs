@@ -0,0 +1,15 @@
+use axum::{middleware, routing::{get, post}, Router};
+use crate::storage::Storage;
+
+use super::{
+    handler::{change_email, delete_me, get_me, update_me, verify_email_change},
+    rate_limit::{limit_auth, AuthLimits},
+};
+
+pub fn me_route(limits: AuthLimits) -> Router<Storage> {
+    // The email change sends and checks an OTP, it is limited like the login
+    Router::new().route("/email", post(change_email))
+    .route("/email/verify", post(verify_email_change))
+    .route_layer(middleware::from_fn_with_state(limits, limit_auth))
+    .route("/", get(get_me).patch(update_me).delete(delete_me))
+}
@@ -1,5 +1,14 @@
 pub mod app;
+pub mod auth;
+pub mod docs_route;
 pub mod error;
 pub mod friend_route;
 pub mod handler;
+pub mod health_route;
+pub mod layers;
+pub mod me_route;
+pub mod outbox_route;
 pub mod public_route;
+pub mod rate_limit;
+pub mod web;
+pub mod web_route;
@@ -0,0 +1,8 @@
+use axum::{routing::get, Router};
+use crate::storage::Storage;
+
+use super::handler::get_message_status;
+
+pub fn outbox_route() -> Router<Storage> {
+    Router::new().route("/:id", get(get_message_status))
+}
@@ -1,11 +1,15 @@
-use axum::{routing::post, Router};
-use sqlx::{Pool, Postgres};
+use axum::{middleware, routing::post, Router};
+use crate::storage::Storage;
 
-use super::handler::{login, signup, verify_otp};
+use super::{
+    handler::{login, logout, signup, verify_otp},
+    rate_limit::{limit_auth, AuthLimits},
+};
 
-pub fn public_route() -> Router<Pool<Postgres>> {
+pub fn public_route(limits: AuthLimits) -> Router<Storage> {
     Router::new().route("/signup", post(signup))
     .route("/login", post(login))
     .route("/verifyOtp", post(verify_otp))
-    //.route("/logout", get(logout))
+    .route_layer(middleware::from_fn_with_state(limits, limit_auth))
+    .route("/logout", post(logout))
 }
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::helper::utils::normalize_email;
+
+use super::error::ApiError;
+
+// Once this many keys are tracked, the windows that are over are dropped
+const PRUNE_AT: usize = 10_000;
+
+// Limiter counts the requests of each key (an IP or an email) in a fixed window
+pub struct Limiter {
+    max: u32,
+    window: Duration,
+    hits: Mutex<HashMap<String, Window>>,
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Limiter {
+    pub fn new(max: u32, window: Duration) -> Limiter {
+        Limiter {
+            max,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // hit counts one request of the key, it returns how long to wait once the window is used up
+    pub fn hit(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap_or_else(|err| err.into_inner());
+        if hits.len() >= PRUNE_AT {
+            hits.retain(|_, window| now.duration_since(window.started) < self.window);
+        }
+        let window = hits.entry(key.to_string()).or_insert(Window { started: now, count: 0 });
+        if now.duration_since(window.started) >= self.window {
+            *window = Window { started: now, count: 0 };
+        }
+        if window.count >= self.max {
+            return Err(self.window - now.duration_since(window.started));
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
+
+// AuthLimits limits the requests to the endpoints that send or check an OTP
+// RATE_LIMIT_IP_MAX requests per RATE_LIMIT_IP_WINDOW_SECONDS from one IP (default 30 per 60s)
+// RATE_LIMIT_EMAIL_MAX requests per RATE_LIMIT_EMAIL_WINDOW_SECONDS for one email (default 10 per 900s)
+// RATE_LIMIT_TRUST_FORWARDED=true takes the IP from X-Forwarded-For, only set it behind a proxy
+#[derive(Clone)]
+pub struct AuthLimits {
+    ip: Arc<Limiter>,
+    email: Arc<Limiter>,
+    trust_forwarded: bool,
+}
+
+impl AuthLimits {
+    pub fn new(ip: Limiter, email: Limiter, trust_forwarded: bool) -> AuthLimits {
+        AuthLimits {
+            ip: Arc::new(ip),
+            email: Arc::new(email),
+            trust_forwarded,
+        }
+    }
+
+    pub fn from_env() -> AuthLimits {
+        let number = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(default)
+        };
+        let ip = Limiter::new(
+            number("RATE_LIMIT_IP_MAX", 30) as u32,
+            Duration::from_secs(number("RATE_LIMIT_IP_WINDOW_SECONDS", 60)),
+        );
+        let email = Limiter::new(
+            number("RATE_LIMIT_EMAIL_MAX", 10) as u32,
+            Duration::from_secs(number("RATE_LIMIT_EMAIL_WINDOW_SECONDS", 900)),
+        );
+        let trust_forwarded = env::var("RATE_LIMIT_TRUST_FORWARDED").is_ok_and(|value| value == "true");
+        AuthLimits::new(ip, email, trust_forwarded)
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, connect: Option<SocketAddr>) -> String {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|ip| ip.trim().to_string())
+            .filter(|_| self.trust_forwarded);
+        match (forwarded, connect) {
+            (Some(ip), _) => ip,
+            (None, Some(address)) => address.ip().to_string(),
+            (None, None) => "unknown".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EmailField {
+    email: String,
+}
+
+// limit_auth refuses the request with 429 once its IP, or the email in its body, made too many requests
+// The body is JSON for the API and a form for the web UI
+pub async fn limit_auth(
+    State(limits): State<AuthLimits>,
+    connect: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let ip = limits.client_ip(request.headers(), connect.map(|ConnectInfo(address)| address));
+    limits.ip.hit(&ip).map_err(too_many_requests)?;
+
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| ApiError::BadRequest("Failed to read the request body".to_string()))?;
+    let form = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    let field: Option<EmailField> = if form {
+        serde_urlencoded::from_bytes(&bytes).ok()
+    } else {
+        serde_json::from_slice(&bytes).ok()
+    };
+    if let Some(field) = field {
+        limits.email.hit(&normalize_email(&field.email)).map_err(too_many_requests)?;
+    }
+    Ok(next.run(Request::from_parts(parts, Body::from(bytes))).await)
+}
+
+fn too_many_requests(wait: Duration) -> ApiError {
+    // Retry-After is in whole seconds, rounded up so the client does not come back too early
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    ApiError::TooManyRequests(retry_after)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Limiter;
+
+    #[test]
+    fn test_limiter() {
+        let limiter = Limiter::new(2, Duration::from_secs(60));
+        assert!(limiter.hit("asha@example.com").is_ok());
+        assert!(limiter.hit("asha@example.com").is_ok());
+        let wait = limiter.hit("asha@example.com").unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        // Every key has its own window
+        assert!(limiter.hit("ravi@example.com").is_ok());
+
+        let limiter = Limiter::new(1, Duration::ZERO);
+        assert!(limiter.hit("asha@example.com").is_ok());
+        assert!(limiter.hit("asha@example.com").is_ok());
+    }
+}
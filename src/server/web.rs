@@ -0,0 +1,523 @@
+use std::fmt::Display;
+
+use askama::Template;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{request::Parts, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar, WithRejection,
+};
+use chrono::{Datelike, Local, NaiveDate};
+use serde::Deserialize;
+
+use crate::{
+    helper::{locale::Locale, utils::normalize_email},
+    schema::{
+        api::{EnteredOtp, LoginUser},
+        friend::{Friend, FriendListQuery, FriendSort, NewFriend},
+        otps::LOGIN,
+        session::Session,
+        user::User,
+    },
+    server::error::{FieldError, FriendError, ValidationError},
+    storage::Storage,
+};
+
+use super::{
+    auth::{session_cookie_secure, CurrentUser, SESSION_COOKIE},
+    error::ApiError,
+    handler::confirm_otp,
+};
+
+// The web UI is a set of server-rendered pages to log in with an OTP and manage the friends
+// It opens the same sessions as /verifyOtp, the token is kept in the session cookie
+
+#[derive(Template)]
+#[template(path = "web/login.html")]
+struct LoginPage {
+    email: String,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "web/otp.html")]
+struct OtpPage {
+    email: String,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "web/friends.html")]
+struct FriendsPage {
+    user_name: String,
+    q: String,
+    sort: FriendSort,
+    sorts: &'static [FriendSort],
+    rows: Vec<FriendRow>,
+    total: i64,
+    next_cursor: Option<String>,
+}
+
+// FriendRow is a friend of the list with their upcoming birthday
+struct FriendRow {
+    friend: Friend,
+    next_birthday: String,
+    turns: i32,
+    days_left: i64,
+}
+
+#[derive(Template)]
+#[template(path = "web/friend_form.html")]
+struct FriendFormPage {
+    user_name: String,
+    title: &'static str,
+    action: String,
+    form: FriendForm,
+    locales: &'static [Locale],
+    errors: Vec<FieldError>,
+}
+
+impl FriendFormPage {
+    // error is the message shown under the field when it is not valid
+    fn error(&self, field: &str) -> Option<&str> {
+        let error = self.errors.iter().find(|error| error.field == field);
+        error.map(|error| error.message.as_str())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "web/preview.html")]
+struct PreviewPage {
+    user_name: String,
+    friend: Friend,
+    date: String,
+    subject: String,
+    body: String,
+}
+
+#[derive(Template)]
+#[template(path = "web/error.html")]
+struct ErrorPage {
+    status: u16,
+    message: String,
+}
+
+// FriendForm is the body of the add and edit forms
+// The date comes as text so a bad date is reported next to the field like the others
+#[derive(Default, Deserialize)]
+pub struct FriendForm {
+    name: String,
+    email: String,
+    dob: String,
+    locale: String,
+}
+
+impl FriendForm {
+    fn from_friend(friend: &Friend) -> FriendForm {
+        FriendForm {
+            name: friend.name.clone(),
+            email: friend.email.clone(),
+            dob: friend.dob.to_string(),
+            locale: Locale::resolve(&friend.locale).code().to_string(),
+        }
+    }
+
+    // parse checks the form like the API checks its body, every invalid field is reported at once
+    fn parse(&self) -> Result<NewFriend, ValidationError> {
+        let dob = NaiveDate::parse_from_str(self.dob.trim(), "%Y-%m-%d").ok();
+        let friend = NewFriend {
+            name: self.name.clone(),
+            email: normalize_email(&self.email),
+            dob: dob.unwrap_or_default(),
+            locale: Locale::resolve(&self.locale).code().to_string(),
+        };
+        let mut errors = friend.validate().err().unwrap_or_default();
+        if dob.is_none() {
+            errors.check("dob", Err("Please enter a date like 1990-08-05".to_string()));
+        }
+        errors.into_result()?;
+        Ok(friend)
+    }
+}
+
+// FriendsQuery is the query string of the friend list, it lists the next birthdays first
+#[derive(Deserialize)]
+pub struct FriendsQuery {
+    q: Option<String>,
+    sort: Option<FriendSort>,
+    cursor: Option<String>,
+}
+
+// LoggedIn is the user of the page, pages redirect to the login form without a valid session
+pub struct LoggedIn(CurrentUser);
+
+#[async_trait]
+impl FromRequestParts<Storage> for LoggedIn {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, pool: &Storage) -> Result<Self, Self::Rejection> {
+        match CurrentUser::from_request_parts(parts, pool).await {
+            Ok(current) => Ok(LoggedIn(current)),
+            Err(ApiError::Unauthorized(_)) => Err(Redirect::to("/ui/login").into_response()),
+            Err(err) => Err(WebError(err).into_response()),
+        }
+    }
+}
+
+// WebError shows an ApiError as an HTML page
+pub struct WebError(ApiError);
+
+impl<E: Into<ApiError>> From<E> for WebError {
+    fn from(err: E) -> Self {
+        WebError(err.into())
+    }
+}
+
+impl IntoResponse for WebError {
+    fn into_response(self) -> Response {
+        let status = self.0.status();
+        let page = ErrorPage {
+            status: status.as_u16(),
+            message: self.0.message(),
+        };
+        render(status, page)
+    }
+}
+
+// render turns a page into a response, a template that fails to render is a server error
+fn render(status: StatusCode, page: impl Template) -> Response {
+    match page.render() {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(err) => server_error(err),
+    }
+}
+
+fn server_error(err: impl Display) -> Response {
+    tracing::error!("Failed to render page: {}", err);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+}
+
+pub async fn home() -> Redirect {
+    Redirect::to("/ui/friends")
+}
+
+pub async fn login_page() -> Response {
+    let page = LoginPage {
+        email: String::new(),
+        error: None,
+    };
+    render(StatusCode::OK, page)
+}
+
+// login sends a login OTP to the user and asks for it
+pub async fn login(
+    State(pool): State<Storage>,
+    WithRejection(Form(user), _): WithRejection<Form<LoginUser>, WebError>,
+) -> Result<Response, WebError> {
+    let found = match user.validate() {
+        Ok(()) => User::get_user_for_login(&pool, &user.email).await.map_err(ApiError::from),
+        Err(err) => Err(err.into()),
+    };
+    let found = match found {
+        Ok(found) => found,
+        Err(err) if err.status().is_client_error() => {
+            let page = LoginPage {
+                email: user.email,
+                error: Some(field_message(err)),
+            };
+            return Ok(render(StatusCode::BAD_REQUEST, page));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    found.send_otp(LOGIN.to_string(), &pool).await?;
+    let page = OtpPage {
+        email: user.email,
+        error: None,
+    };
+    Ok(render(StatusCode::OK, page))
+}
+
+// verify checks the OTP, opens a session and keeps its token in the session cookie
+pub async fn verify(
+    State(pool): State<Storage>,
+    jar: CookieJar,
+    WithRejection(Form(entered_otp), _): WithRejection<Form<EnteredOtp>, WebError>,
+) -> Result<Response, WebError> {
+    let email = entered_otp.email.clone();
+    let user = match confirm_otp(&pool, entered_otp).await {
+        Ok(user) => user,
+        Err(err) if err.status().is_client_error() => {
+            let page = OtpPage {
+                email,
+                error: Some(field_message(err)),
+            };
+            return Ok(render(StatusCode::BAD_REQUEST, page));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let session = Session::start(&pool, &user).await?;
+    let cookie = Cookie::build((SESSION_COOKIE, session.token))
+        .path("/ui")
+        .http_only(true)
+        .secure(session_cookie_secure())
+        .same_site(SameSite::Lax);
+    Ok((jar.add(cookie), Redirect::to("/ui/friends")).into_response())
+}
+
+pub async fn logout(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    jar: CookieJar,
+) -> Result<Response, WebError> {
+    Session::end(&pool, &current.token).await?;
+    let jar = jar.remove(Cookie::build(SESSION_COOKIE).path("/ui"));
+    Ok((jar, Redirect::to("/ui/login")).into_response())
+}
+
+// friends lists the friends with their upcoming birthday, a page at a time
+pub async fn friends(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    WithRejection(Query(query), _): WithRejection<Query<FriendsQuery>, WebError>,
+) -> Result<Response, WebError> {
+    let query = FriendListQuery {
+        limit: None,
+        cursor: query.cursor,
+        sort: query.sort.unwrap_or(FriendSort::NextBirthday),
+        q: query.q,
+    };
+    let (limit, after) = query.page()?;
+    let page = Friend::search(&pool, Some(&current.user), query.search(), query.sort, limit, after).await?;
+    let today = Local::now().date_naive();
+    let rows = page
+        .friends
+        .into_iter()
+        .map(|friend| {
+            let next = friend.next_birthday(today);
+            FriendRow {
+                next_birthday: Locale::En.format_date(next),
+                turns: next.year() - friend.dob.year(),
+                days_left: (next - today).num_days(),
+                friend,
+            }
+        })
+        .collect();
+    let page = FriendsPage {
+        user_name: current.user.name,
+        q: query.search().unwrap_or_default().to_string(),
+        sort: query.sort,
+        sorts: FriendSort::OPTIONS,
+        rows,
+        total: page.total,
+        next_cursor: page.next_cursor,
+    };
+    Ok(render(StatusCode::OK, page))
+}
+
+pub async fn new_friend(LoggedIn(current): LoggedIn) -> Response {
+    let page = friend_form(current, "Add a friend", "/ui/friends".to_string(), FriendForm::default());
+    render(StatusCode::OK, page)
+}
+
+pub async fn create_friend(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    WithRejection(Form(form), _): WithRejection<Form<FriendForm>, WebError>,
+) -> Result<Response, WebError> {
+    let owner = current.user.clone();
+    let page = friend_form(current, "Add a friend", "/ui/friends".to_string(), form);
+    let friend = match page.form.parse() {
+        Ok(friend) => friend.add(&pool, Some(&owner)).await,
+        Err(err) => return Ok(form_errors(page, err.errors)),
+    };
+    saved(page, friend)
+}
+
+pub async fn edit_friend(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    Path(id): Path<i32>,
+) -> Result<Response, WebError> {
+    let friend = Friend::get_owned_friend(&pool, id, &current.user).await?;
+    let form = FriendForm::from_friend(&friend);
+    let page = friend_form(current, "Edit friend", format!("/ui/friends/{}", id), form);
+    Ok(render(StatusCode::OK, page))
+}
+
+pub async fn update_friend(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    Path(id): Path<i32>,
+    WithRejection(Form(form), _): WithRejection<Form<FriendForm>, WebError>,
+) -> Result<Response, WebError> {
+    Friend::get_owned_friend(&pool, id, &current.user).await?;
+    let page = friend_form(current, "Edit friend", format!("/ui/friends/{}", id), form);
+    let friend = match page.form.parse() {
+        Ok(friend) => friend.update(&pool, id).await,
+        Err(err) => return Ok(form_errors(page, err.errors)),
+    };
+    saved(page, friend)
+}
+
+pub async fn delete_friend(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    Path(id): Path<i32>,
+) -> Result<Redirect, WebError> {
+    let friend = Friend::get_owned_friend(&pool, id, &current.user).await?;
+    friend.remove_friend(&pool).await?;
+    Ok(Redirect::to("/ui/friends"))
+}
+
+// preview shows the wishes the friend will get on their next birthday
+pub async fn preview_friend(
+    State(pool): State<Storage>,
+    LoggedIn(current): LoggedIn,
+    Path(id): Path<i32>,
+) -> Result<Response, WebError> {
+    let friend = Friend::get_owned_friend(&pool, id, &current.user).await?;
+    let next = friend.next_birthday(Local::now().date_naive());
+    let (subject, body) = match friend.render_wishes(next) {
+        Ok(wishes) => wishes,
+        Err(err) => return Ok(server_error(err)),
+    };
+    let page = PreviewPage {
+        user_name: current.user.name,
+        date: Locale::En.format_date(next),
+        friend,
+        subject,
+        body,
+    };
+    Ok(render(StatusCode::OK, page))
+}
+
+fn friend_form(current: CurrentUser, title: &'static str, action: String, form: FriendForm) -> FriendFormPage {
+    FriendFormPage {
+        user_name: current.user.name,
+        title,
+        action,
+        form,
+        locales: Locale::OPTIONS,
+        errors: Vec::new(),
+    }
+}
+
+fn form_errors(mut page: FriendFormPage, errors: Vec<FieldError>) -> Response {
+    page.errors = errors;
+    render(StatusCode::BAD_REQUEST, page)
+}
+
+// saved goes back to the list once the friend is saved, an email already in the list is shown on the form
+fn saved(page: FriendFormPage, friend: Result<Friend, FriendError>) -> Result<Response, WebError> {
+    match friend {
+        Ok(_) => Ok(Redirect::to("/ui/friends").into_response()),
+        Err(err @ FriendError::FriendAlreadyExist) => {
+            let message = err.to_string();
+            Ok(form_errors(page, vec![FieldError { field: "email", message }]))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// field_message is the message shown on a form, the first invalid field when there are several
+fn field_message(err: ApiError) -> String {
+    match err {
+        ApiError::Validation(err) => err.errors.into_iter().next().map(|error| error.message).unwrap_or_default(),
+        err => err.message(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::{Path, Query, State},
+        http::StatusCode,
+        response::IntoResponse,
+        Form,
+    };
+    use axum_extra::extract::WithRejection;
+    use chrono::NaiveDate;
+
+    use crate::{
+        schema::{
+            api::NewUser,
+            friend::{Friend, NewFriend},
+        },
+        server::auth::CurrentUser,
+        storage::{memory::MemoryStore, Storage, UserRepository},
+    };
+
+    use super::{delete_friend, edit_friend, friends, preview_friend, update_friend, FriendForm, FriendsQuery, LoggedIn};
+
+    #[test]
+    fn test_friend_form_parse() {
+        let form = FriendForm {
+            name: "Ravi".to_string(),
+            email: " Ravi@Example.com ".to_string(),
+            dob: "1990-08-05".to_string(),
+            locale: "es-MX".to_string(),
+        };
+        let friend = form.parse().unwrap();
+        assert_eq!((friend.email.as_str(), friend.locale.as_str()), ("ravi@example.com", "es"));
+
+        let form = FriendForm { name: String::new(), dob: "05/08/1990".to_string(), ..form };
+        let Err(err) = form.parse() else {
+            panic!("An empty name and a bad date were accepted");
+        };
+        let fields: Vec<&str> = err.errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, ["name", "dob"]);
+    }
+
+    #[tokio::test]
+    async fn test_friends_of_other_users() {
+        let conn = Storage::Memory(MemoryStore::default());
+        let user = |email: &str| NewUser {
+            name: "Asha".to_string(),
+            email: email.to_string(),
+            locale: "en".to_string(),
+        };
+        let asha = conn.insert_user(&user("asha@example.com")).await.unwrap();
+        let kim = conn.insert_user(&user("kim@example.com")).await.unwrap();
+        let friend = NewFriend {
+            name: "Ravi".to_string(),
+            email: "ravi@example.com".to_string(),
+            dob: NaiveDate::from_ymd_opt(1990, 8, 5).unwrap(),
+            locale: "en".to_string(),
+        };
+        let ravi = friend.add(&conn, Some(&asha)).await.unwrap();
+        let logged_in = |user| LoggedIn(CurrentUser { user, token: "token".to_string() });
+
+        // Kim does not see the friends Asha added
+        let query = FriendsQuery { q: None, sort: None, cursor: None };
+        let response = friends(State(conn.clone()), logged_in(kim.clone()), WithRejection(Query(query), Default::default()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("0 friend(s)") && !body.contains("Ravi"));
+
+        // and can not open, change or remove them
+        let response = edit_friend(State(conn.clone()), logged_in(kim.clone()), Path(ravi.id)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        let response = preview_friend(State(conn.clone()), logged_in(kim.clone()), Path(ravi.id)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        let form = FriendForm::from_friend(&Friend { name: "Ravi K".to_string(), ..ravi.clone() });
+        let response =
+            update_friend(State(conn.clone()), logged_in(kim.clone()), Path(ravi.id), WithRejection(Form(form), Default::default()))
+                .await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        let response = delete_friend(State(conn.clone()), logged_in(kim), Path(ravi.id)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+        assert_eq!(Friend::get_friend(&conn, ravi.id).await.unwrap().name, "Ravi");
+
+        // Asha still can
+        let response = preview_friend(State(conn.clone()), logged_in(asha), Path(ravi.id)).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+    }
+}
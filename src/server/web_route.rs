@@ -0,0 +1,28 @@
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use crate::storage::Storage;
+
+use super::rate_limit::{limit_auth, AuthLimits};
+use super::web::{
+    create_friend, delete_friend, edit_friend, friends, home, login, login_page, logout,
+    new_friend, preview_friend, update_friend, verify,
+};
+
+// The login form shares the rate limits of the API endpoints it stands for
+pub fn web_route(limits: AuthLimits) -> Router<Storage> {
+    let limit = middleware::from_fn_with_state(limits, limit_auth);
+    Router::new()
+        .route("/", get(home))
+        .route("/login", post(login).route_layer(limit.clone()).get(login_page))
+        .route("/verify", post(verify).route_layer(limit))
+        .route("/logout", post(logout))
+        .route("/friends", get(friends).post(create_friend))
+        .route("/friends/new", get(new_friend))
+        .route("/friends/:id", post(update_friend))
+        .route("/friends/:id/edit", get(edit_friend))
+        .route("/friends/:id/delete", post(delete_friend))
+        .route("/friends/:id/preview", get(preview_friend))
+}
@@ -0,0 +1,511 @@
+use std::{
+    borrow::Cow,
+    error::Error as StdError,
+    fmt::{Display, Formatter},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use sqlx::{
+    error::{DatabaseError, ErrorKind},
+    Error,
+};
+
+use crate::schema::{
+    api::{NewUser, UpdateUser},
+    friend::{Friend, FriendSort, NewFriend},
+    otps::Otp,
+    outbox::{MessageStatus, NewMessage, OutboxEntry, FAILED, OTP, PENDING, SENT},
+    session::Session,
+    user::User,
+};
+
+use super::{
+    birthday_days, birthday_rank, FriendRepository, FriendSearch, OtpRepository, OutboxRepository,
+    SessionRepository, UserRepository, CLAIM_LEASE_SECONDS,
+};
+
+// MemoryStore keeps every table in the process, it behaves like the databases
+// (ids, dedupe keys, cascades) so handlers and the runner can be tested without one
+// Clones share the same tables
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    tables: Arc<Mutex<Tables>>,
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    friends: Vec<Friend>,
+    users: Vec<User>,
+    otps: Vec<Otp>,
+    sessions: Vec<Session>,
+    outbox: Vec<Message>,
+    last_friend_id: i32,
+    last_user_id: i32,
+    last_otp_id: i32,
+    last_message_id: i32,
+}
+
+#[derive(Debug)]
+struct Message {
+    id: i32,
+    kind: String,
+    friend_id: Option<i32>,
+    user_id: Option<i32>,
+    recipient: String,
+    subject: String,
+    body: String,
+    status: String,
+    attempts: i32,
+    last_error: Option<String>,
+    next_attempt_at: DateTime<Utc>,
+    deadline: DateTime<Utc>,
+    dedupe_key: Option<String>,
+    created_at: DateTime<Utc>,
+    sent_at: Option<DateTime<Utc>>,
+}
+
+// UniqueViolation is what the databases return when an email is already taken
+#[derive(Debug)]
+struct UniqueViolation(&'static str);
+
+impl Display for UniqueViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "duplicate key value violates unique constraint \"{}\"", self.0)
+    }
+}
+
+impl StdError for UniqueViolation {}
+
+impl DatabaseError for UniqueViolation {
+    fn message(&self) -> &str {
+        "duplicate key value violates unique constraint"
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed("23505"))
+    }
+
+    fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+        self
+    }
+
+    fn constraint(&self) -> Option<&str> {
+        Some(self.0)
+    }
+
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::UniqueViolation
+    }
+}
+
+impl MemoryStore {
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // A panic in another test thread should not hide this test's result
+        self.tables.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Tables {
+    fn enqueue(&mut self, message: &NewMessage<'_>) -> Option<i32> {
+        if message.dedupe_key.is_some()
+            && self.outbox.iter().any(|queued| queued.dedupe_key == message.dedupe_key)
+        {
+            return None;
+        }
+        self.last_message_id += 1;
+        let now = Utc::now();
+        self.outbox.push(Message {
+            id: self.last_message_id,
+            kind: message.kind.to_string(),
+            friend_id: message.friend_id,
+            user_id: message.user_id,
+            recipient: message.recipient.to_string(),
+            subject: message.subject.clone(),
+            body: message.body.clone(),
+            status: PENDING.to_string(),
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: now,
+            deadline: message.deadline,
+            dedupe_key: message.dedupe_key.clone(),
+            created_at: now,
+            sent_at: None,
+        });
+        Some(self.last_message_id)
+    }
+
+    fn message(&mut self, id: i32) -> Option<&mut Message> {
+        self.outbox.iter_mut().find(|message| message.id == id)
+    }
+}
+
+impl FriendRepository for MemoryStore {
+    async fn get_friend(&self, id: i32) -> Result<Friend, Error> {
+        let tables = self.tables();
+        let friend = tables.friends.iter().find(|friend| friend.id == id);
+        friend.cloned().ok_or(Error::RowNotFound)
+    }
+
+    async fn get_friends(&self) -> Result<Vec<Friend>, Error> {
+        Ok(self.tables().friends.clone())
+    }
+
+    async fn insert_friend(&self, friend: &NewFriend, owner: Option<i32>) -> Result<Friend, Error> {
+        let mut tables = self.tables();
+        let email = friend.email.to_lowercase();
+        if tables.friends.iter().any(|saved| saved.owner_id == owner && saved.email.to_lowercase() == email) {
+            return Err(Error::Database(Box::new(UniqueViolation("friend_owner_email_key"))));
+        }
+        tables.last_friend_id += 1;
+        let friend = Friend {
+            id: tables.last_friend_id,
+            name: friend.name.clone(),
+            email: friend.email.clone(),
+            dob: friend.dob,
+            locale: friend.locale.clone(),
+            owner_id: owner,
+        };
+        tables.friends.push(friend.clone());
+        Ok(friend)
+    }
+
+    async fn update_friend(&self, id: i32, friend: &NewFriend) -> Result<Friend, Error> {
+        let mut tables = self.tables();
+        let email = friend.email.to_lowercase();
+        let owner = tables.friends.iter().find(|saved| saved.id == id).map(|saved| saved.owner_id);
+        let taken = tables.friends.iter().any(|saved| {
+            saved.id != id && Some(saved.owner_id) == owner && saved.email.to_lowercase() == email
+        });
+        if taken {
+            return Err(Error::Database(Box::new(UniqueViolation("friend_owner_email_key"))));
+        }
+        let saved = tables
+            .friends
+            .iter_mut()
+            .find(|saved| saved.id == id)
+            .ok_or(Error::RowNotFound)?;
+        saved.name = friend.name.clone();
+        saved.email = friend.email.clone();
+        saved.dob = friend.dob;
+        saved.locale = friend.locale.clone();
+        Ok(saved.clone())
+    }
+
+    async fn delete_friend(&self, id: i32) -> Result<Friend, Error> {
+        let mut tables = self.tables();
+        let index = tables
+            .friends
+            .iter()
+            .position(|friend| friend.id == id)
+            .ok_or(Error::RowNotFound)?;
+        // Like the foreign key, the friend's queued wishes go with them
+        tables.outbox.retain(|message| message.friend_id != Some(id));
+        Ok(tables.friends.remove(index))
+    }
+
+    async fn get_birthday_friends(&self, today: NaiveDate) -> Result<Vec<Friend>, Error> {
+        let (month, days) = birthday_days(today);
+        let tables = self.tables();
+        let friends = tables.friends.iter().filter(|friend| {
+            let day = friend.dob.day() as i32;
+            friend.dob.month() as i32 == month && (day == days.0 || day == days.1)
+        });
+        Ok(friends.cloned().collect())
+    }
+
+    async fn search_friends(
+        &self,
+        search: &FriendSearch<'_>,
+        today: NaiveDate,
+    ) -> Result<Vec<Friend>, Error> {
+        let tables = self.tables();
+        let mut friends: Vec<Friend> = tables
+            .friends
+            .iter()
+            .filter(|friend| owned_by(friend, search.owner_id) && matches(friend, search.search))
+            .cloned()
+            .collect();
+        // The friends are kept in id order, the stable sorts keep it for ties
+        match search.sort {
+            FriendSort::Name => friends.sort_by_key(|friend| friend.name.to_lowercase()),
+            FriendSort::NextBirthday => friends.sort_by_key(|friend| birthday_rank(friend.dob, today)),
+            FriendSort::Created => {}
+        }
+        // The page starts after the (sort key, id) of the cursor, like the queries compare it
+        let (after_id, after_name, after_rank) = search.keyset(today);
+        let after = |friend: &Friend| match (after_id, after_name, after_rank) {
+            (None, _, _) => true,
+            (Some(id), Some(name), _) => (friend.name.to_lowercase(), friend.id) > (name.to_lowercase(), id),
+            (Some(id), _, Some(rank)) => (birthday_rank(friend.dob, today), friend.id) > (rank, id),
+            (Some(id), None, None) => friend.id > id,
+        };
+        let page = friends
+            .into_iter()
+            .filter(after)
+            .take(search.limit.max(0) as usize);
+        Ok(page.collect())
+    }
+
+    async fn count_friends(&self, owner_id: Option<i32>, search: Option<&str>) -> Result<i64, Error> {
+        let tables = self.tables();
+        let count = tables
+            .friends
+            .iter()
+            .filter(|friend| owned_by(friend, owner_id) && matches(friend, search))
+            .count();
+        Ok(count as i64)
+    }
+}
+
+// owned_by keeps the friends added by the owner, every friend when it is None
+fn owned_by(friend: &Friend, owner_id: Option<i32>) -> bool {
+    owner_id.is_none() || friend.owner_id == owner_id
+}
+
+// matches finds the search anywhere in the name or the email of the friend, ignoring case
+fn matches(friend: &Friend, search: Option<&str>) -> bool {
+    let Some(search) = search.map(str::to_lowercase) else {
+        return true;
+    };
+    friend.name.to_lowercase().contains(&search) || friend.email.to_lowercase().contains(&search)
+}
+
+impl UserRepository for MemoryStore {
+    async fn get_user_by_email(&self, email: &str) -> Result<User, Error> {
+        let tables = self.tables();
+        let user = tables.users.iter().find(|user| user.email.to_lowercase() == email.to_lowercase());
+        user.cloned().ok_or(Error::RowNotFound)
+    }
+
+    async fn insert_user(&self, user: &NewUser) -> Result<User, Error> {
+        let mut tables = self.tables();
+        if tables.users.iter().any(|saved| saved.email.to_lowercase() == user.email.to_lowercase()) {
+            return Err(Error::Database(Box::new(UniqueViolation("users_email_key"))));
+        }
+        tables.last_user_id += 1;
+        let user = User {
+            id: tables.last_user_id,
+            name: user.name.clone(),
+            email: user.email.clone(),
+            locale: user.locale.clone(),
+            created_at: Utc::now(),
+            verified_at: None,
+        };
+        tables.users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn update_user(&self, id: i32, update: &UpdateUser) -> Result<User, Error> {
+        let mut tables = self.tables();
+        let user = tables.users.iter_mut().find(|user| user.id == id).ok_or(Error::RowNotFound)?;
+        if let Some(name) = &update.name {
+            user.name = name.clone();
+        }
+        if let Some(locale) = &update.locale {
+            user.locale = locale.clone();
+        }
+        Ok(user.clone())
+    }
+
+    async fn update_user_email(&self, id: i32, email: &str) -> Result<User, Error> {
+        let mut tables = self.tables();
+        let taken = tables
+            .users
+            .iter()
+            .any(|user| user.id != id && user.email.to_lowercase() == email.to_lowercase());
+        if taken {
+            return Err(Error::Database(Box::new(UniqueViolation("users_email_key"))));
+        }
+        let user = tables.users.iter_mut().find(|user| user.id == id).ok_or(Error::RowNotFound)?;
+        user.email = email.to_string();
+        Ok(user.clone())
+    }
+
+    async fn delete_user(&self, user: &User) -> Result<(), Error> {
+        let mut tables = self.tables();
+        let owned: Vec<i32> = tables
+            .friends
+            .iter()
+            .filter(|friend| friend.owner_id == Some(user.id))
+            .map(|friend| friend.id)
+            .collect();
+        tables.friends.retain(|friend| !owned.contains(&friend.id));
+        tables.outbox.retain(|message| {
+            let friend_gone = message.friend_id.is_some_and(|id| owned.contains(&id));
+            !friend_gone && message.user_id != Some(user.id)
+        });
+        tables.otps.retain(|otp| otp.user_id != user.id);
+        tables.sessions.retain(|session| session.user_id != user.id);
+        tables.users.retain(|saved| saved.id != user.id);
+        Ok(())
+    }
+
+    async fn verify_user(&self, id: i32) -> Result<User, Error> {
+        let mut tables = self.tables();
+        let user = tables.users.iter_mut().find(|user| user.id == id).ok_or(Error::RowNotFound)?;
+        user.verified_at.get_or_insert_with(Utc::now);
+        Ok(user.clone())
+    }
+
+    async fn get_unverified_users(&self, created_before: DateTime<Utc>) -> Result<Vec<User>, Error> {
+        let tables = self.tables();
+        let users = tables
+            .users
+            .iter()
+            .filter(|user| user.verified_at.is_none() && user.created_at < created_before);
+        Ok(users.cloned().collect())
+    }
+}
+
+impl SessionRepository for MemoryStore {
+    async fn insert_session(&self, session: &Session) -> Result<(), Error> {
+        self.tables().sessions.push(session.clone());
+        Ok(())
+    }
+
+    async fn get_session_user(&self, token: &str) -> Result<User, Error> {
+        let tables = self.tables();
+        let session = tables
+            .sessions
+            .iter()
+            .find(|session| session.token == token && session.expires_at > Utc::now())
+            .ok_or(Error::RowNotFound)?;
+        let user = tables.users.iter().find(|user| user.id == session.user_id);
+        user.cloned().ok_or(Error::RowNotFound)
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<(), Error> {
+        self.tables().sessions.retain(|session| session.token != token);
+        Ok(())
+    }
+}
+
+impl OtpRepository for MemoryStore {
+    async fn save_otp_with_message(
+        &self,
+        otp: &Otp,
+        message: &NewMessage<'_>,
+    ) -> Result<Option<i32>, Error> {
+        let mut tables = self.tables();
+        let outbox_id = tables.enqueue(message);
+        tables.last_otp_id += 1;
+        let id = tables.last_otp_id;
+        tables.otps.push(Otp { id, ..otp.clone() });
+        Ok(outbox_id)
+    }
+
+    async fn get_otp(&self, email: &str) -> Result<Otp, Error> {
+        let tables = self.tables();
+        let otp = tables.otps.iter().rev().find(|otp| otp.email.to_lowercase() == email.to_lowercase());
+        otp.cloned().ok_or(Error::RowNotFound)
+    }
+
+    async fn delete_otp(&self, otp: &Otp) -> Result<(), Error> {
+        self.tables().otps.retain(|saved| saved.id != otp.id);
+        Ok(())
+    }
+
+    async fn add_otp_attempt(&self, otp: &Otp) -> Result<i32, Error> {
+        let mut tables = self.tables();
+        let saved = tables.otps.iter_mut().find(|saved| saved.id == otp.id).ok_or(Error::RowNotFound)?;
+        saved.attempts += 1;
+        Ok(saved.attempts)
+    }
+}
+
+impl OutboxRepository for MemoryStore {
+    async fn enqueue(&self, message: &NewMessage<'_>) -> Result<Option<i32>, Error> {
+        Ok(self.tables().enqueue(message))
+    }
+
+    async fn claim_due(&self, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        let now = Utc::now();
+        let mut tables = self.tables();
+        let mut due: Vec<&mut Message> = tables
+            .outbox
+            .iter_mut()
+            .filter(|message| {
+                message.status == PENDING && message.next_attempt_at <= now && message.deadline > now
+            })
+            .collect();
+        due.sort_by_key(|message| (message.kind != OTP, message.next_attempt_at));
+        let entries = due
+            .into_iter()
+            .take(limit.max(0) as usize)
+            .map(|message| {
+                message.next_attempt_at = now + Duration::seconds(CLAIM_LEASE_SECONDS);
+                OutboxEntry {
+                    id: message.id,
+                    kind: message.kind.clone(),
+                    recipient: message.recipient.clone(),
+                    subject: message.subject.clone(),
+                    body: message.body.clone(),
+                    attempts: message.attempts,
+                    deadline: message.deadline,
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    async fn give_up_expired(&self) -> Result<u64, Error> {
+        let now = Utc::now();
+        let mut count = 0;
+        for message in self.tables().outbox.iter_mut() {
+            if message.status == PENDING && message.deadline <= now {
+                message.status = FAILED.to_string();
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<(), Error> {
+        if let Some(message) = self.tables().message(id) {
+            message.status = SENT.to_string();
+            message.attempts += 1;
+            message.last_error = None;
+            message.sent_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i32,
+        status: &str,
+        attempts: i32,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if let Some(message) = self.tables().message(id) {
+            message.status = status.to_string();
+            message.attempts = attempts;
+            message.last_error = Some(error.to_string());
+            message.next_attempt_at = next_attempt_at;
+        }
+        Ok(())
+    }
+
+    async fn get_status(&self, id: i32) -> Result<MessageStatus, Error> {
+        let mut tables = self.tables();
+        let message = tables.message(id).ok_or(Error::RowNotFound)?;
+        Ok(MessageStatus {
+            id: message.id,
+            kind: message.kind.clone(),
+            status: message.status.clone(),
+            attempts: message.attempts,
+            created_at: message.created_at,
+            sent_at: message.sent_at,
+        })
+    }
+}
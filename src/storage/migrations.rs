@@ -0,0 +1,120 @@
+use std::env;
+
+use sqlx::{
+    migrate::{AppliedMigration, Migrate, MigrateError, Migrator},
+    Database, Pool,
+};
+use tabled::Tabled;
+
+use super::Storage;
+
+// The migrations are embedded in the binary, each backend has its own directory
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations-sqlite");
+
+// Status of a migration
+const APPLIED: &str = "applied";
+const PENDING: &str = "pending";
+// The migration was applied, but its file has changed since
+const CHANGED: &str = "changed";
+
+// MigrationStatus is one embedded migration and whether the database has it
+#[derive(Tabled)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub status: &'static str,
+}
+
+// AUTO_MIGRATE=true applies the pending migrations when `serve` or `run` starts (default false)
+pub fn auto_migrate() -> bool {
+    env::var("AUTO_MIGRATE")
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+async fn applied_migrations<DB>(pool: &Pool<DB>) -> Result<Vec<AppliedMigration>, MigrateError>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    conn.list_applied_migrations().await
+}
+
+impl Storage {
+    // migrate_up applies every pending migration
+    // The in-memory store has no schema, there is nothing to do for it
+    pub async fn migrate_up(&self) -> Result<(), MigrateError> {
+        match self {
+            Storage::Postgres(pool) => POSTGRES_MIGRATIONS.run(pool).await,
+            Storage::Sqlite(pool) => SQLITE_MIGRATIONS.run(pool).await,
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+
+    // migrate_down reverts the last applied migration and returns its version
+    // It returns None when no migration is applied
+    pub async fn migrate_down(&self) -> Result<Option<i64>, MigrateError> {
+        let mut versions: Vec<i64> = self
+            .applied_migrations()
+            .await?
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        versions.sort();
+        let Some(last) = versions.pop() else {
+            return Ok(None);
+        };
+        // undo reverts everything newer than the target, so target the one before the last
+        let target = versions.last().copied().unwrap_or(0);
+        match self {
+            Storage::Postgres(pool) => POSTGRES_MIGRATIONS.undo(pool, target).await?,
+            Storage::Sqlite(pool) => SQLITE_MIGRATIONS.undo(pool, target).await?,
+            Storage::Memory(_) => {}
+        }
+        Ok(Some(last))
+    }
+
+    // migration_status lists the embedded migrations and whether each one is applied
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>, MigrateError> {
+        let migrator = match self {
+            Storage::Postgres(_) => &POSTGRES_MIGRATIONS,
+            Storage::Sqlite(_) => &SQLITE_MIGRATIONS,
+            Storage::Memory(_) => return Ok(Vec::new()),
+        };
+        let applied = self.applied_migrations().await?;
+        let status = migrator
+            .iter()
+            .filter(|migration| migration.migration_type.is_up_migration())
+            .map(|migration| {
+                let status = match applied.iter().find(|done| done.version == migration.version) {
+                    Some(done) if done.checksum != migration.checksum => CHANGED,
+                    Some(_) => APPLIED,
+                    None => PENDING,
+                };
+                MigrationStatus {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                    status,
+                }
+            })
+            .collect();
+        Ok(status)
+    }
+
+    // is_up_to_date is true when every embedded migration is applied and unchanged
+    pub async fn is_up_to_date(&self) -> Result<bool, MigrateError> {
+        let status = self.migration_status().await?;
+        Ok(status.iter().all(|migration| migration.status == APPLIED))
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, MigrateError> {
+        match self {
+            Storage::Postgres(pool) => applied_migrations(pool).await,
+            Storage::Sqlite(pool) => applied_migrations(pool).await,
+            Storage::Memory(_) => Ok(Vec::new()),
+        }
+    }
+}
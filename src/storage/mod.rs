@@ -0,0 +1,358 @@
+pub mod memory;
+pub mod migrations;
+pub mod postgres;
+pub mod sqlite;
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use sqlx::{Error, PgPool, SqlitePool};
+
+use crate::schema::{
+    api::{NewUser, UpdateUser},
+    friend::{Friend, FriendCursor, FriendSort, NewFriend},
+    otps::Otp,
+    outbox::{MessageStatus, NewMessage, OutboxEntry},
+    session::Session,
+    user::User,
+};
+
+use self::memory::MemoryStore;
+
+// How long a worker holds on to the outbox entries it claimed before someone else may pick them up
+pub const CLAIM_LEASE_SECONDS: i64 = 300;
+
+// Storage is the database the app runs on
+// The backend is picked from the DATABASE_URL scheme: `postgres://` or `sqlite://`,
+// the in-memory store keeps everything in the process and is used by the tests
+#[derive(Clone, Debug)]
+pub enum Storage {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+    Memory(MemoryStore),
+}
+
+impl Storage {
+    // ping checks that the database answers a query
+    pub async fn ping(&self) -> Result<(), Error> {
+        match self {
+            Storage::Postgres(pool) => sqlx::query("SELECT 1").execute(pool).await.map(|_| ()),
+            Storage::Sqlite(pool) => sqlx::query("SELECT 1").execute(pool).await.map(|_| ()),
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+
+    // pool_stats returns the open and idle connections of the pool, the in-memory store has none
+    pub fn pool_stats(&self) -> Option<(u32, usize)> {
+        match self {
+            Storage::Postgres(pool) => Some((pool.size(), pool.num_idle())),
+            Storage::Sqlite(pool) => Some((pool.size(), pool.num_idle())),
+            Storage::Memory(_) => None,
+        }
+    }
+}
+
+// FriendRepository stores the friends we send wishes to
+pub trait FriendRepository {
+    async fn get_friend(&self, id: i32) -> Result<Friend, Error>;
+    async fn get_friends(&self) -> Result<Vec<Friend>, Error>;
+    // insert_friend adds the friend, owner is the user who added them if any
+    async fn insert_friend(&self, friend: &NewFriend, owner: Option<i32>) -> Result<Friend, Error>;
+    async fn update_friend(&self, id: i32, friend: &NewFriend) -> Result<Friend, Error>;
+    async fn delete_friend(&self, id: i32) -> Result<Friend, Error>;
+    // get_birthday_friends returns the friends whose birthday is on the given date
+    async fn get_birthday_friends(&self, today: NaiveDate) -> Result<Vec<Friend>, Error>;
+    // search_friends returns one page of the friends matching the search
+    // today is the day `next_birthday` counts from
+    async fn search_friends(
+        &self,
+        search: &FriendSearch<'_>,
+        today: NaiveDate,
+    ) -> Result<Vec<Friend>, Error>;
+    // count_friends counts the friends of the owner matching the search, every friend when both are None
+    async fn count_friends(&self, owner_id: Option<i32>, search: Option<&str>) -> Result<i64, Error>;
+}
+
+// FriendSearch selects a page of the friend list
+// owner_id keeps the friends added by that user, search is found anywhere in the name or the email, ignoring case
+// The page holds the friends that come after the cursor in the sort order
+pub struct FriendSearch<'a> {
+    pub owner_id: Option<i32>,
+    pub search: Option<&'a str>,
+    pub sort: FriendSort,
+    pub limit: i64,
+    pub after: Option<FriendCursor>,
+}
+
+impl FriendSearch<'_> {
+    // keyset is what the queries compare the rows to: the id of the cursor,
+    // with its name or the rank of its birthday when the list is sorted by them
+    pub fn keyset(&self, today: NaiveDate) -> (Option<i32>, Option<&str>, Option<i32>) {
+        let id = self.after.as_ref().map(FriendCursor::id);
+        match &self.after {
+            Some(FriendCursor::Name { name, .. }) => (id, Some(name), None),
+            Some(FriendCursor::NextBirthday { day, .. }) => (id, None, Some(day_rank(*day, today))),
+            _ => (id, None, None),
+        }
+    }
+}
+
+// UserRepository stores the users of the API
+pub trait UserRepository {
+    async fn get_user_by_email(&self, email: &str) -> Result<User, Error>;
+    async fn insert_user(&self, user: &NewUser) -> Result<User, Error>;
+    // update_user changes the fields that are set, the others keep their value
+    async fn update_user(&self, id: i32, update: &UpdateUser) -> Result<User, Error>;
+    async fn update_user_email(&self, id: i32, email: &str) -> Result<User, Error>;
+    // delete_user removes the user with the friends they own, their OTPs and every email sent
+    // to them, at any address they had, or to their friends
+    async fn delete_user(&self, user: &User) -> Result<(), Error>;
+    // verify_user records that the signup OTP was entered, the first time only
+    async fn verify_user(&self, id: i32) -> Result<User, Error>;
+    // get_unverified_users lists the accounts created before the time that were never verified
+    async fn get_unverified_users(&self, created_before: DateTime<Utc>) -> Result<Vec<User>, Error>;
+}
+
+// SessionRepository stores the sessions opened by verifying an OTP
+pub trait SessionRepository {
+    async fn insert_session(&self, session: &Session) -> Result<(), Error>;
+    // get_session_user returns the user the session belongs to, RowNotFound once it expired
+    async fn get_session_user(&self, token: &str) -> Result<User, Error>;
+    async fn delete_session(&self, token: &str) -> Result<(), Error>;
+}
+
+// OtpRepository stores the OTPs sent to users
+pub trait OtpRepository {
+    // save_otp_with_message stores the OTP and queues its email in one transaction
+    async fn save_otp_with_message(
+        &self,
+        otp: &Otp,
+        message: &NewMessage<'_>,
+    ) -> Result<Option<i32>, Error>;
+    async fn get_otp(&self, email: &str) -> Result<Otp, Error>;
+    async fn delete_otp(&self, otp: &Otp) -> Result<(), Error>;
+    // add_otp_attempt counts a wrong code entered for the OTP and returns the new count
+    async fn add_otp_attempt(&self, otp: &Otp) -> Result<i32, Error>;
+}
+
+// OutboxRepository stores the emails waiting to be delivered
+pub trait OutboxRepository {
+    async fn enqueue(&self, message: &NewMessage<'_>) -> Result<Option<i32>, Error>;
+    // claim_due leases due entries to one worker, OTP emails first so a user logging in
+    // does not wait behind a day of birthday wishes
+    async fn claim_due(&self, limit: i64) -> Result<Vec<OutboxEntry>, Error>;
+    async fn give_up_expired(&self) -> Result<u64, Error>;
+    async fn mark_sent(&self, id: i32) -> Result<(), Error>;
+    async fn mark_failed(
+        &self,
+        id: i32,
+        status: &str,
+        attempts: i32,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    async fn get_status(&self, id: i32) -> Result<MessageStatus, Error>;
+}
+
+// birthday_days returns the month and the days of that month whose birthdays fall on `today`
+// Friends born on February 29 are wished on February 28 when the year is not a leap year
+pub fn birthday_days(today: NaiveDate) -> (i32, (i32, i32)) {
+    let (month, day) = (today.month() as i32, today.day() as i32);
+    let leap_year = NaiveDate::from_ymd_opt(today.year(), 2, 29).is_some();
+    if month == 2 && day == 28 && !leap_year {
+        (month, (28, 29))
+    } else {
+        (month, (day, day))
+    }
+}
+
+// like_pattern turns a search text into a case-insensitive LIKE pattern matching it anywhere
+// `%`, `_` and `\` are escaped so they only match themselves (the queries use ESCAPE '\')
+pub fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .to_lowercase()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+// Days are numbered month * 32 + day, so a year fits in 13 * 32 numbers
+pub const BIRTHDAY_YEAR: i32 = 13 * 32;
+
+// birthday_rank orders birthdays by how soon they come after `today`, today's birthdays first
+// The queries compute the same number from the month and the day of the dob
+pub fn birthday_rank(dob: NaiveDate, today: NaiveDate) -> i32 {
+    day_rank(day_number(dob), today)
+}
+
+fn day_rank(day: i32, today: NaiveDate) -> i32 {
+    (day - day_number(today) + BIRTHDAY_YEAR) % BIRTHDAY_YEAR
+}
+
+pub fn day_number(date: NaiveDate) -> i32 {
+    date.month() as i32 * 32 + date.day() as i32
+}
+
+// dispatch calls the repository method on whichever backend the storage runs on
+macro_rules! dispatch {
+    ($storage:expr, $method:ident($($arg:expr),*)) => {
+        match $storage {
+            Storage::Postgres(conn) => conn.$method($($arg),*).await,
+            Storage::Sqlite(conn) => conn.$method($($arg),*).await,
+            Storage::Memory(conn) => conn.$method($($arg),*).await,
+        }
+    };
+}
+
+impl FriendRepository for Storage {
+    async fn get_friend(&self, id: i32) -> Result<Friend, Error> {
+        dispatch!(self, get_friend(id))
+    }
+
+    async fn get_friends(&self) -> Result<Vec<Friend>, Error> {
+        dispatch!(self, get_friends())
+    }
+
+    async fn insert_friend(&self, friend: &NewFriend, owner: Option<i32>) -> Result<Friend, Error> {
+        dispatch!(self, insert_friend(friend, owner))
+    }
+
+    async fn update_friend(&self, id: i32, friend: &NewFriend) -> Result<Friend, Error> {
+        dispatch!(self, update_friend(id, friend))
+    }
+
+    async fn delete_friend(&self, id: i32) -> Result<Friend, Error> {
+        dispatch!(self, delete_friend(id))
+    }
+
+    async fn get_birthday_friends(&self, today: NaiveDate) -> Result<Vec<Friend>, Error> {
+        dispatch!(self, get_birthday_friends(today))
+    }
+
+    async fn search_friends(
+        &self,
+        search: &FriendSearch<'_>,
+        today: NaiveDate,
+    ) -> Result<Vec<Friend>, Error> {
+        dispatch!(self, search_friends(search, today))
+    }
+
+    async fn count_friends(&self, owner_id: Option<i32>, search: Option<&str>) -> Result<i64, Error> {
+        dispatch!(self, count_friends(owner_id, search))
+    }
+}
+
+impl UserRepository for Storage {
+    async fn get_user_by_email(&self, email: &str) -> Result<User, Error> {
+        dispatch!(self, get_user_by_email(email))
+    }
+
+    async fn insert_user(&self, user: &NewUser) -> Result<User, Error> {
+        dispatch!(self, insert_user(user))
+    }
+
+    async fn update_user(&self, id: i32, update: &UpdateUser) -> Result<User, Error> {
+        dispatch!(self, update_user(id, update))
+    }
+
+    async fn update_user_email(&self, id: i32, email: &str) -> Result<User, Error> {
+        dispatch!(self, update_user_email(id, email))
+    }
+
+    async fn delete_user(&self, user: &User) -> Result<(), Error> {
+        dispatch!(self, delete_user(user))
+    }
+
+    async fn verify_user(&self, id: i32) -> Result<User, Error> {
+        dispatch!(self, verify_user(id))
+    }
+
+    async fn get_unverified_users(&self, created_before: DateTime<Utc>) -> Result<Vec<User>, Error> {
+        dispatch!(self, get_unverified_users(created_before))
+    }
+}
+
+impl SessionRepository for Storage {
+    async fn insert_session(&self, session: &Session) -> Result<(), Error> {
+        dispatch!(self, insert_session(session))
+    }
+
+    async fn get_session_user(&self, token: &str) -> Result<User, Error> {
+        dispatch!(self, get_session_user(token))
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<(), Error> {
+        dispatch!(self, delete_session(token))
+    }
+}
+
+impl OtpRepository for Storage {
+    async fn save_otp_with_message(
+        &self,
+        otp: &Otp,
+        message: &NewMessage<'_>,
+    ) -> Result<Option<i32>, Error> {
+        dispatch!(self, save_otp_with_message(otp, message))
+    }
+
+    async fn get_otp(&self, email: &str) -> Result<Otp, Error> {
+        dispatch!(self, get_otp(email))
+    }
+
+    async fn delete_otp(&self, otp: &Otp) -> Result<(), Error> {
+        dispatch!(self, delete_otp(otp))
+    }
+
+    async fn add_otp_attempt(&self, otp: &Otp) -> Result<i32, Error> {
+        dispatch!(self, add_otp_attempt(otp))
+    }
+}
+
+impl OutboxRepository for Storage {
+    async fn enqueue(&self, message: &NewMessage<'_>) -> Result<Option<i32>, Error> {
+        dispatch!(self, enqueue(message))
+    }
+
+    async fn claim_due(&self, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        dispatch!(self, claim_due(limit))
+    }
+
+    async fn give_up_expired(&self) -> Result<u64, Error> {
+        dispatch!(self, give_up_expired())
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<(), Error> {
+        dispatch!(self, mark_sent(id))
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i32,
+        status: &str,
+        attempts: i32,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        dispatch!(self, mark_failed(id, status, attempts, error, next_attempt_at))
+    }
+
+    async fn get_status(&self, id: i32) -> Result<MessageStatus, Error> {
+        dispatch!(self, get_status(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::birthday_days;
+
+    #[test]
+    fn test_birthday_days() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(birthday_days(date(2024, 10, 19)), (10, (19, 19)));
+        // 2023 is not a leap year, February 29 birthdays are on the 28th
+        assert_eq!(birthday_days(date(2023, 2, 28)), (2, (28, 29)));
+        assert_eq!(birthday_days(date(2024, 2, 28)), (2, (28, 28)));
+        assert_eq!(birthday_days(date(2024, 2, 29)), (2, (29, 29)));
+    }
+}
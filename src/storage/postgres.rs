@@ -0,0 +1,397 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{Error, PgExecutor, PgPool};
+
+use crate::schema::{
+    api::{NewUser, UpdateUser},
+    friend::{Friend, NewFriend},
+    otps::Otp,
+    outbox::{MessageStatus, NewMessage, OutboxEntry, FAILED, OTP, PENDING, SENT},
+    session::Session,
+    user::User,
+};
+
+use super::{
+    birthday_days, day_number, like_pattern, FriendRepository, FriendSearch, OtpRepository,
+    OutboxRepository, SessionRepository, UserRepository, BIRTHDAY_YEAR, CLAIM_LEASE_SECONDS,
+};
+
+impl FriendRepository for PgPool {
+    async fn get_friend(&self, id: i32) -> Result<Friend, Error> {
+        sqlx::query_as!(Friend, "SELECT * FROM friend WHERE id = ($1)", id)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn get_friends(&self) -> Result<Vec<Friend>, Error> {
+        sqlx::query_as!(Friend, "SELECT * FROM friend",)
+            .fetch_all(self)
+            .await
+    }
+
+    async fn insert_friend(&self, friend: &NewFriend, owner: Option<i32>) -> Result<Friend, Error> {
+        sqlx::query_as!(
+            Friend,
+            "INSERT INTO friend (name, email, dob, locale, owner_id) VALUES($1, $2, $3, $4, $5) RETURNING *",
+            friend.name,
+            friend.email,
+            friend.dob,
+            friend.locale,
+            owner
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_friend(&self, id: i32, friend: &NewFriend) -> Result<Friend, Error> {
+        sqlx::query_as!(
+            Friend,
+            "UPDATE friend SET name = $2, email = $3, dob = $4, locale = $5 WHERE id = $1 RETURNING *",
+            id,
+            friend.name,
+            friend.email,
+            friend.dob,
+            friend.locale
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_friend(&self, id: i32) -> Result<Friend, Error> {
+        sqlx::query_as!(Friend, "DELETE FROM friend WHERE id = ($1) RETURNING *", id)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn get_birthday_friends(&self, today: NaiveDate) -> Result<Vec<Friend>, Error> {
+        let (month, days) = birthday_days(today);
+        sqlx::query_as!(
+            Friend,
+            r#"
+            SELECT * FROM friend
+            WHERE EXTRACT(MONTH FROM dob)::INTEGER = $1
+            AND EXTRACT(DAY FROM dob)::INTEGER IN ($2, $3)
+            "#,
+            month,
+            days.0,
+            days.1
+        )
+        .fetch_all(self)
+        .await
+    }
+
+    async fn search_friends(
+        &self,
+        search: &FriendSearch<'_>,
+        today: NaiveDate,
+    ) -> Result<Vec<Friend>, Error> {
+        // Rows are ordered by the sort key first, and by id so pages never overlap
+        // The page starts after the (sort key, id) of the cursor
+        let (after_id, after_name, after_rank) = search.keyset(today);
+        sqlx::query_as!(
+            Friend,
+            r#"
+            SELECT * FROM friend
+            WHERE ($1::TEXT IS NULL OR lower(name) LIKE $1 ESCAPE '\' OR lower(email) LIKE $1 ESCAPE '\')
+            AND ($9::INTEGER IS NULL OR owner_id = $9)
+            AND (
+                $6::INTEGER IS NULL
+                OR ($2 = 'created' AND id > $6)
+                OR ($2 = 'name' AND (lower(name), id) > (lower($7::TEXT), $6))
+                OR ($2 = 'next_birthday' AND (
+                    (EXTRACT(MONTH FROM dob)::INTEGER * 32 + EXTRACT(DAY FROM dob)::INTEGER - $3 + $4) % $4, id
+                ) > ($8::INTEGER, $6))
+            )
+            ORDER BY
+                CASE WHEN $2 = 'name' THEN lower(name) END,
+                CASE WHEN $2 = 'next_birthday' THEN
+                    (EXTRACT(MONTH FROM dob)::INTEGER * 32 + EXTRACT(DAY FROM dob)::INTEGER - $3 + $4) % $4
+                END,
+                id
+            LIMIT $5
+            "#,
+            search.search.map(like_pattern),
+            search.sort.as_str(),
+            day_number(today),
+            BIRTHDAY_YEAR,
+            search.limit,
+            after_id,
+            after_name,
+            after_rank,
+            search.owner_id
+        )
+        .fetch_all(self)
+        .await
+    }
+
+    async fn count_friends(&self, owner_id: Option<i32>, search: Option<&str>) -> Result<i64, Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!" FROM friend
+            WHERE ($1::TEXT IS NULL OR lower(name) LIKE $1 ESCAPE '\' OR lower(email) LIKE $1 ESCAPE '\')
+            AND ($2::INTEGER IS NULL OR owner_id = $2)
+            "#,
+            search.map(like_pattern),
+            owner_id
+        )
+        .fetch_one(self)
+        .await
+    }
+}
+
+impl UserRepository for PgPool {
+    async fn get_user_by_email(&self, email: &str) -> Result<User, Error> {
+        sqlx::query_as!(User, "SELECT * FROM users WHERE lower(email) = lower($1)", email)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn insert_user(&self, user: &NewUser) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            "INSERT INTO users (name, email, locale) VALUES ($1, $2, $3) RETURNING *",
+            user.name,
+            user.email,
+            user.locale
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_user(&self, id: i32, update: &UpdateUser) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET name = COALESCE($2, name), locale = COALESCE($3, locale)
+            WHERE id = $1 RETURNING *
+            "#,
+            id,
+            update.name,
+            update.locale
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_user_email(&self, id: i32, email: &str) -> Result<User, Error> {
+        sqlx::query_as!(User, "UPDATE users SET email = $2 WHERE id = $1 RETURNING *", id, email)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn delete_user(&self, user: &User) -> Result<(), Error> {
+        // The friends they own go with the user through the foreign key, with the wishes queued
+        // or sent to them, and so do the sessions, the OTPs and their emails, whatever address they went to
+        sqlx::query!("DELETE FROM users WHERE id = $1", user.id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn verify_user(&self, id: i32) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            "UPDATE users SET verified_at = COALESCE(verified_at, now()) WHERE id = $1 RETURNING *",
+            id
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn get_unverified_users(&self, created_before: DateTime<Utc>) -> Result<Vec<User>, Error> {
+        sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE verified_at IS NULL AND created_at < $1",
+            created_before
+        )
+        .fetch_all(self)
+        .await
+    }
+}
+
+impl SessionRepository for PgPool {
+    async fn insert_session(&self, session: &Session) -> Result<(), Error> {
+        sqlx::query!(
+            "INSERT INTO sessions (token, user_id, expires_at) VALUES ($1, $2, $3)",
+            session.token,
+            session.user_id,
+            session.expires_at
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_session_user(&self, token: &str) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id
+            WHERE sessions.token = $1 AND sessions.expires_at > now()
+            "#,
+            token
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<(), Error> {
+        sqlx::query!("DELETE FROM sessions WHERE token = $1", token)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+impl OtpRepository for PgPool {
+    async fn save_otp_with_message(
+        &self,
+        otp: &Otp,
+        message: &NewMessage<'_>,
+    ) -> Result<Option<i32>, Error> {
+        let mut transaction = self.begin().await?;
+        let outbox_id = enqueue_message(&mut *transaction, message).await?;
+        sqlx::query!(
+            "INSERT INTO otps (user_id, email, otp, created_for, used, outbox_id, expires_at, attempts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            otp.user_id,
+            otp.email,
+            otp.otp,
+            otp.created_for,
+            otp.used,
+            outbox_id,
+            otp.expires_at,
+            otp.attempts
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(outbox_id)
+    }
+
+    async fn get_otp(&self, email: &str) -> Result<Otp, Error> {
+        // The latest OTP is the one the user has just received
+        sqlx::query_as!(
+            Otp,
+            "SELECT id, user_id, email, otp, created_for, used, expires_at, attempts FROM otps
+            WHERE lower(email) = lower($1) ORDER BY id DESC LIMIT 1",
+            email
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_otp(&self, otp: &Otp) -> Result<(), Error> {
+        sqlx::query!("DELETE FROM otps WHERE id = $1", otp.id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_otp_attempt(&self, otp: &Otp) -> Result<i32, Error> {
+        sqlx::query_scalar!("UPDATE otps SET attempts = attempts + 1 WHERE id = $1 RETURNING attempts", otp.id)
+            .fetch_one(self)
+            .await
+    }
+}
+
+async fn enqueue_message<'c>(
+    executor: impl PgExecutor<'c>,
+    message: &NewMessage<'_>,
+) -> Result<Option<i32>, Error> {
+    sqlx::query_scalar!(
+        "INSERT INTO outbox (kind, friend_id, user_id, recipient, subject, body, deadline, dedupe_key)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (dedupe_key) DO NOTHING RETURNING id",
+        message.kind,
+        message.friend_id,
+        message.user_id,
+        message.recipient,
+        message.subject,
+        message.body,
+        message.deadline,
+        message.dedupe_key
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+impl OutboxRepository for PgPool {
+    async fn enqueue(&self, message: &NewMessage<'_>) -> Result<Option<i32>, Error> {
+        enqueue_message(self, message).await
+    }
+
+    async fn claim_due(&self, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        sqlx::query_as!(
+            OutboxEntry,
+            "UPDATE outbox SET next_attempt_at = now() + make_interval(secs => $1)
+            WHERE id IN (
+                SELECT id FROM outbox
+                WHERE status = $2 AND next_attempt_at <= now() AND deadline > now()
+                ORDER BY (kind = $4) DESC, next_attempt_at
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, recipient, subject, body, attempts, deadline",
+            CLAIM_LEASE_SECONDS as f64,
+            PENDING,
+            limit,
+            OTP
+        )
+        .fetch_all(self)
+        .await
+    }
+
+    async fn give_up_expired(&self) -> Result<u64, Error> {
+        let result = sqlx::query!(
+            "UPDATE outbox SET status = $1 WHERE status = $2 AND deadline <= now()",
+            FAILED,
+            PENDING
+        )
+        .execute(self)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE outbox SET status = $1, attempts = attempts + 1, last_error = NULL, sent_at = now()
+            WHERE id = $2",
+            SENT,
+            id
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i32,
+        status: &str,
+        attempts: i32,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE outbox SET status = $1, attempts = $2, last_error = $3, next_attempt_at = $4 WHERE id = $5",
+            status,
+            attempts,
+            error,
+            next_attempt_at,
+            id
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_status(&self, id: i32) -> Result<MessageStatus, Error> {
+        sqlx::query_as!(
+            MessageStatus,
+            "SELECT id, kind, status, attempts, created_at, sent_at FROM outbox WHERE id = $1",
+            id
+        )
+        .fetch_one(self)
+        .await
+    }
+}
@@ -0,0 +1,596 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use sqlx::{Error, SqliteExecutor, SqlitePool};
+
+use crate::schema::{
+    api::{NewUser, UpdateUser},
+    friend::{Friend, NewFriend},
+    otps::Otp,
+    outbox::{MessageStatus, NewMessage, OutboxEntry, FAILED, OTP, PENDING, SENT},
+    session::Session,
+    user::User,
+};
+
+use super::{
+    birthday_days, day_number, like_pattern, FriendRepository, FriendSearch, OtpRepository,
+    OutboxRepository, SessionRepository, UserRepository, BIRTHDAY_YEAR, CLAIM_LEASE_SECONDS,
+};
+
+// SQLite has no timestamp type, timestamps are stored as RFC 3339 text
+// and compared through julianday() so different precisions still order correctly
+
+impl FriendRepository for SqlitePool {
+    async fn get_friend(&self, id: i32) -> Result<Friend, Error> {
+        sqlx::query_as("SELECT * FROM friend WHERE id = ?1")
+            .bind(id)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn get_friends(&self) -> Result<Vec<Friend>, Error> {
+        sqlx::query_as("SELECT * FROM friend")
+            .fetch_all(self)
+            .await
+    }
+
+    async fn insert_friend(&self, friend: &NewFriend, owner: Option<i32>) -> Result<Friend, Error> {
+        sqlx::query_as(
+            "INSERT INTO friend (name, email, dob, locale, owner_id) VALUES (?1, ?2, ?3, ?4, ?5) RETURNING *",
+        )
+        .bind(&friend.name)
+        .bind(&friend.email)
+        .bind(friend.dob)
+        .bind(&friend.locale)
+        .bind(owner)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_friend(&self, id: i32, friend: &NewFriend) -> Result<Friend, Error> {
+        sqlx::query_as(
+            "UPDATE friend SET name = ?2, email = ?3, dob = ?4, locale = ?5 WHERE id = ?1 RETURNING *",
+        )
+        .bind(id)
+        .bind(&friend.name)
+        .bind(&friend.email)
+        .bind(friend.dob)
+        .bind(&friend.locale)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_friend(&self, id: i32) -> Result<Friend, Error> {
+        sqlx::query_as("DELETE FROM friend WHERE id = ?1 RETURNING *")
+            .bind(id)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn get_birthday_friends(&self, today: NaiveDate) -> Result<Vec<Friend>, Error> {
+        let (month, days) = birthday_days(today);
+        sqlx::query_as(
+            "SELECT * FROM friend
+            WHERE CAST(strftime('%m', dob) AS INTEGER) = ?1
+            AND CAST(strftime('%d', dob) AS INTEGER) IN (?2, ?3)",
+        )
+        .bind(month)
+        .bind(days.0)
+        .bind(days.1)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn search_friends(
+        &self,
+        search: &FriendSearch<'_>,
+        today: NaiveDate,
+    ) -> Result<Vec<Friend>, Error> {
+        // Rows are ordered by the sort key first, and by id so pages never overlap
+        // The page starts after the (sort key, id) of the cursor
+        let (after_id, after_name, after_rank) = search.keyset(today);
+        sqlx::query_as(
+            "SELECT * FROM friend
+            WHERE (?1 IS NULL OR lower(name) LIKE ?1 ESCAPE '\\' OR lower(email) LIKE ?1 ESCAPE '\\')
+            AND (?9 IS NULL OR owner_id = ?9)
+            AND (
+                ?6 IS NULL
+                OR (?2 = 'created' AND id > ?6)
+                OR (?2 = 'name' AND (lower(name), id) > (lower(?7), ?6))
+                OR (?2 = 'next_birthday' AND (
+                    (CAST(strftime('%m', dob) AS INTEGER) * 32 + CAST(strftime('%d', dob) AS INTEGER) - ?3 + ?4) % ?4, id
+                ) > (?8, ?6))
+            )
+            ORDER BY
+                CASE WHEN ?2 = 'name' THEN lower(name) END,
+                CASE WHEN ?2 = 'next_birthday' THEN
+                    (CAST(strftime('%m', dob) AS INTEGER) * 32 + CAST(strftime('%d', dob) AS INTEGER) - ?3 + ?4) % ?4
+                END,
+                id
+            LIMIT ?5",
+        )
+        .bind(search.search.map(like_pattern))
+        .bind(search.sort.as_str())
+        .bind(day_number(today))
+        .bind(BIRTHDAY_YEAR)
+        .bind(search.limit)
+        .bind(after_id)
+        .bind(after_name)
+        .bind(after_rank)
+        .bind(search.owner_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn count_friends(&self, owner_id: Option<i32>, search: Option<&str>) -> Result<i64, Error> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM friend
+            WHERE (?1 IS NULL OR lower(name) LIKE ?1 ESCAPE '\\' OR lower(email) LIKE ?1 ESCAPE '\\')
+            AND (?2 IS NULL OR owner_id = ?2)",
+        )
+        .bind(search.map(like_pattern))
+        .bind(owner_id)
+        .fetch_one(self)
+        .await
+    }
+}
+
+impl UserRepository for SqlitePool {
+    async fn get_user_by_email(&self, email: &str) -> Result<User, Error> {
+        sqlx::query_as("SELECT * FROM users WHERE lower(email) = lower(?1)")
+            .bind(email)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn insert_user(&self, user: &NewUser) -> Result<User, Error> {
+        sqlx::query_as(
+            "INSERT INTO users (name, email, locale, created_at) VALUES (?1, ?2, ?3, ?4) RETURNING *",
+        )
+        .bind(&user.name)
+        .bind(&user.email)
+        .bind(&user.locale)
+        .bind(Utc::now())
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_user(&self, id: i32, update: &UpdateUser) -> Result<User, Error> {
+        sqlx::query_as(
+            "UPDATE users SET name = COALESCE(?2, name), locale = COALESCE(?3, locale)
+            WHERE id = ?1 RETURNING *",
+        )
+        .bind(id)
+        .bind(&update.name)
+        .bind(&update.locale)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn update_user_email(&self, id: i32, email: &str) -> Result<User, Error> {
+        sqlx::query_as("UPDATE users SET email = ?2 WHERE id = ?1 RETURNING *")
+            .bind(id)
+            .bind(email)
+            .fetch_one(self)
+            .await
+    }
+
+    async fn delete_user(&self, user: &User) -> Result<(), Error> {
+        // The friends they own go with the user through the foreign key, with the wishes queued
+        // or sent to them, and so do the sessions, the OTPs and their emails, whatever address they went to
+        sqlx::query("DELETE FROM users WHERE id = ?1")
+            .bind(user.id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn verify_user(&self, id: i32) -> Result<User, Error> {
+        sqlx::query_as("UPDATE users SET verified_at = COALESCE(verified_at, ?2) WHERE id = ?1 RETURNING *")
+            .bind(id)
+            .bind(Utc::now())
+            .fetch_one(self)
+            .await
+    }
+
+    async fn get_unverified_users(&self, created_before: DateTime<Utc>) -> Result<Vec<User>, Error> {
+        sqlx::query_as(
+            "SELECT * FROM users WHERE verified_at IS NULL AND julianday(created_at) < julianday(?1)",
+        )
+        .bind(created_before)
+        .fetch_all(self)
+        .await
+    }
+}
+
+impl SessionRepository for SqlitePool {
+    async fn insert_session(&self, session: &Session) -> Result<(), Error> {
+        sqlx::query("INSERT INTO sessions (token, user_id, expires_at) VALUES (?1, ?2, ?3)")
+            .bind(&session.token)
+            .bind(session.user_id)
+            .bind(session.expires_at)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_session_user(&self, token: &str) -> Result<User, Error> {
+        sqlx::query_as(
+            "SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id
+            WHERE sessions.token = ?1 AND julianday(sessions.expires_at) > julianday(?2)",
+        )
+        .bind(token)
+        .bind(Utc::now())
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM sessions WHERE token = ?1")
+            .bind(token)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+impl OtpRepository for SqlitePool {
+    async fn save_otp_with_message(
+        &self,
+        otp: &Otp,
+        message: &NewMessage<'_>,
+    ) -> Result<Option<i32>, Error> {
+        let mut transaction = self.begin().await?;
+        let outbox_id = enqueue_message(&mut *transaction, message).await?;
+        sqlx::query(
+            "INSERT INTO otps (user_id, email, otp, created_for, used, outbox_id, expires_at, attempts)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )
+        .bind(otp.user_id)
+        .bind(&otp.email)
+        .bind(&otp.otp)
+        .bind(&otp.created_for)
+        .bind(otp.used)
+        .bind(outbox_id)
+        .bind(otp.expires_at)
+        .bind(otp.attempts)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(outbox_id)
+    }
+
+    async fn get_otp(&self, email: &str) -> Result<Otp, Error> {
+        // The latest OTP is the one the user has just received
+        sqlx::query_as(
+            "SELECT id, user_id, email, otp, created_for, used, expires_at, attempts FROM otps
+            WHERE lower(email) = lower(?1) ORDER BY id DESC LIMIT 1",
+        )
+        .bind(email)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn delete_otp(&self, otp: &Otp) -> Result<(), Error> {
+        sqlx::query("DELETE FROM otps WHERE id = ?1")
+            .bind(otp.id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_otp_attempt(&self, otp: &Otp) -> Result<i32, Error> {
+        sqlx::query_scalar("UPDATE otps SET attempts = attempts + 1 WHERE id = ?1 RETURNING attempts")
+            .bind(otp.id)
+            .fetch_one(self)
+            .await
+    }
+}
+
+async fn enqueue_message<'c>(
+    executor: impl SqliteExecutor<'c>,
+    message: &NewMessage<'_>,
+) -> Result<Option<i32>, Error> {
+    let now = Utc::now();
+    sqlx::query_scalar(
+        "INSERT INTO outbox (kind, friend_id, user_id, recipient, subject, body, deadline, dedupe_key, next_attempt_at, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+        ON CONFLICT (dedupe_key) DO NOTHING RETURNING id",
+    )
+    .bind(message.kind)
+    .bind(message.friend_id)
+    .bind(message.user_id)
+    .bind(message.recipient)
+    .bind(&message.subject)
+    .bind(&message.body)
+    .bind(message.deadline)
+    .bind(&message.dedupe_key)
+    .bind(now)
+    .fetch_optional(executor)
+    .await
+}
+
+impl OutboxRepository for SqlitePool {
+    async fn enqueue(&self, message: &NewMessage<'_>) -> Result<Option<i32>, Error> {
+        enqueue_message(self, message).await
+    }
+
+    async fn claim_due(&self, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        // SQLite allows a single writer at a time, so the claim cannot race with another worker
+        let now = Utc::now();
+        sqlx::query_as(
+            "UPDATE outbox SET next_attempt_at = ?1
+            WHERE id IN (
+                SELECT id FROM outbox
+                WHERE status = ?2 AND julianday(next_attempt_at) <= julianday(?3)
+                AND julianday(deadline) > julianday(?3)
+                ORDER BY kind = ?5 DESC, julianday(next_attempt_at)
+                LIMIT ?4
+            )
+            RETURNING id, kind, recipient, subject, body, attempts, deadline",
+        )
+        .bind(now + Duration::seconds(CLAIM_LEASE_SECONDS))
+        .bind(PENDING)
+        .bind(now)
+        .bind(limit)
+        .bind(OTP)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn give_up_expired(&self) -> Result<u64, Error> {
+        let result = sqlx::query(
+            "UPDATE outbox SET status = ?1 WHERE status = ?2 AND julianday(deadline) <= julianday(?3)",
+        )
+        .bind(FAILED)
+        .bind(PENDING)
+        .bind(Utc::now())
+        .execute(self)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE outbox SET status = ?1, attempts = attempts + 1, last_error = NULL, sent_at = ?2
+            WHERE id = ?3",
+        )
+        .bind(SENT)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i32,
+        status: &str,
+        attempts: i32,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE outbox SET status = ?1, attempts = ?2, last_error = ?3, next_attempt_at = ?4 WHERE id = ?5",
+        )
+        .bind(status)
+        .bind(attempts)
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(id)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_status(&self, id: i32) -> Result<MessageStatus, Error> {
+        sqlx::query_as(
+            "SELECT id, kind, status, attempts, created_at, sent_at
+            FROM outbox WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_one(self)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{Duration, NaiveDate, Utc};
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+        SqlitePool,
+    };
+
+    use crate::{
+        schema::{
+            api::NewUser,
+            friend::{Friend, FriendCursor, FriendSort, NewFriend},
+            otps::{Otp, LOGIN},
+            outbox::{NewMessage, OTP},
+        },
+        storage::{FriendRepository, FriendSearch, OtpRepository, Storage, UserRepository},
+    };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    // pool opens a migrated in-memory database
+    // Every connection to `sqlite::memory:` has its own database, so the pool keeps a single one
+    async fn pool() -> SqlitePool {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:").unwrap().foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await
+            .unwrap();
+        Storage::Sqlite(pool.clone()).migrate_up().await.unwrap();
+        pool
+    }
+
+    async fn add(pool: &SqlitePool, name: &str, email: &str, dob: NaiveDate) {
+        let friend = NewFriend {
+            name: name.to_string(),
+            email: email.to_string(),
+            dob,
+            locale: "en".to_string(),
+        };
+        pool.insert_friend(&friend, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_birthday_friends() {
+        let pool = pool().await;
+        add(&pool, "Leap", "leap@example.com", date(2000, 2, 29)).await;
+        add(&pool, "Ravi", "ravi@example.com", date(1990, 2, 28)).await;
+        add(&pool, "Kim", "kim@example.com", date(1985, 3, 1)).await;
+
+        let names = |friends: Vec<Friend>| {
+            let mut names: Vec<String> = friends.into_iter().map(|friend| friend.name).collect();
+            names.sort();
+            names
+        };
+        // February 29 birthdays are on the 28th when the year is not a leap year
+        let friends = pool.get_birthday_friends(date(2027, 2, 28)).await.unwrap();
+        assert_eq!(names(friends), ["Leap", "Ravi"]);
+        let friends = pool.get_birthday_friends(date(2028, 2, 28)).await.unwrap();
+        assert_eq!(names(friends), ["Ravi"]);
+        let friends = pool.get_birthday_friends(date(2028, 2, 29)).await.unwrap();
+        assert_eq!(names(friends), ["Leap"]);
+        let friends = pool.get_birthday_friends(date(2027, 3, 1)).await.unwrap();
+        assert_eq!(names(friends), ["Kim"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_friends() {
+        let pool = pool().await;
+        add(&pool, "100% Ravi", "ravi@example.com", date(1990, 8, 5)).await;
+        add(&pool, "Kim", "kim_lee@example.com", date(1985, 3, 1)).await;
+        add(&pool, "Asha", "asha@example.com", date(1992, 10, 20)).await;
+
+        let search = |search| FriendSearch {
+            owner_id: None,
+            search,
+            sort: FriendSort::Name,
+            limit: 10,
+            after: None,
+        };
+        let names = |friends: Vec<Friend>| {
+            friends.into_iter().map(|friend| friend.name).collect::<Vec<_>>()
+        };
+        let today = date(2026, 10, 19);
+        // `%` and `_` only match themselves
+        let friends = pool.search_friends(&search(Some("%")), today).await.unwrap();
+        assert_eq!(names(friends), ["100% Ravi"]);
+        let friends = pool.search_friends(&search(Some("_")), today).await.unwrap();
+        assert_eq!(names(friends), ["Kim"]);
+        assert_eq!(pool.count_friends(None, Some("_")).await.unwrap(), 1);
+        // The search ignores case
+        let friends = pool.search_friends(&search(Some("ASHA")), today).await.unwrap();
+        assert_eq!(names(friends), ["Asha"]);
+
+        let friends = pool.search_friends(&search(None), today).await.unwrap();
+        assert_eq!(names(friends), ["100% Ravi", "Asha", "Kim"]);
+        let next = FriendSearch { sort: FriendSort::NextBirthday, ..search(None) };
+        let friends = pool.search_friends(&next, today).await.unwrap();
+        assert_eq!(names(friends), ["Asha", "Kim", "100% Ravi"]);
+
+        // A page starts after the sort key and the id of the cursor
+        let after = |after| FriendSearch { after: Some(after), ..search(None) };
+        let cursor = FriendCursor::Name { name: "ASHA".to_string(), id: 3 };
+        let friends = pool.search_friends(&after(cursor), today).await.unwrap();
+        assert_eq!(names(friends), ["Kim"]);
+        let cursor = FriendCursor::NextBirthday { day: 3 * 32 + 1, id: 2 };
+        let next = FriendSearch { sort: FriendSort::NextBirthday, ..after(cursor) };
+        let friends = pool.search_friends(&next, today).await.unwrap();
+        assert_eq!(names(friends), ["100% Ravi"]);
+        let cursor = FriendCursor::Created { id: 1 };
+        let created = FriendSearch { sort: FriendSort::Created, ..after(cursor) };
+        let friends = pool.search_friends(&created, today).await.unwrap();
+        assert_eq!(names(friends), ["Kim", "Asha"]);
+        assert_eq!(pool.count_friends(None, None).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_friend_unique_per_owner() {
+        let pool = pool().await;
+        let user = |email: &str| NewUser {
+            name: "Asha".to_string(),
+            email: email.to_string(),
+            locale: "en".to_string(),
+        };
+        let asha = pool.insert_user(&user("asha@example.com")).await.unwrap();
+        let kim = pool.insert_user(&user("kim@example.com")).await.unwrap();
+        let friend = NewFriend {
+            name: "Ravi".to_string(),
+            email: "ravi@example.com".to_string(),
+            dob: date(1990, 8, 5),
+            locale: "en".to_string(),
+        };
+
+        // Two users may have the same friend, once each
+        pool.insert_friend(&friend, Some(asha.id)).await.unwrap();
+        pool.insert_friend(&friend, Some(kim.id)).await.unwrap();
+        let shouting = NewFriend { email: "RAVI@example.com".to_string(), ..friend.clone() };
+        let err = pool.insert_friend(&shouting, Some(asha.id)).await.unwrap_err();
+        assert!(err.as_database_error().is_some_and(|err| err.is_unique_violation()));
+        // Friends without an owner are unique among themselves
+        pool.insert_friend(&friend, None).await.unwrap();
+        assert!(pool.insert_friend(&friend, None).await.is_err());
+
+        // A user only lists the friends they added
+        let search = FriendSearch { owner_id: Some(kim.id), search: None, sort: FriendSort::Created, limit: 10, after: None };
+        let friends = pool.search_friends(&search, date(2026, 10, 19)).await.unwrap();
+        assert_eq!(friends.iter().map(|friend| friend.owner_id).collect::<Vec<_>>(), [Some(kim.id)]);
+        assert_eq!(pool.count_friends(Some(asha.id), Some("ravi")).await.unwrap(), 1);
+        assert_eq!(pool.count_friends(None, Some("ravi")).await.unwrap(), 3);
+
+        // The friends of a user go with their account
+        pool.delete_user(&asha).await.unwrap();
+        let owners: Vec<Option<i32>> = pool
+            .get_friends()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|friend| friend.owner_id)
+            .collect();
+        assert_eq!(owners, [Some(kim.id), None]);
+    }
+
+    #[tokio::test]
+    async fn test_otp_found_by_id() {
+        let pool = pool().await;
+        let user = NewUser {
+            name: "Asha".to_string(),
+            email: "asha@example.com".to_string(),
+            locale: "en".to_string(),
+        };
+        let asha = pool.insert_user(&user).await.unwrap();
+        let message = NewMessage {
+            kind: OTP,
+            friend_id: None,
+            user_id: Some(asha.id),
+            recipient: "asha@example.com",
+            subject: "OTP".to_string(),
+            body: "OTP".to_string(),
+            deadline: Utc::now() + Duration::minutes(15),
+            dedupe_key: None,
+        };
+        // Two OTPs for the address may share a code, only the one read is counted and deleted
+        let first = Otp::generate(asha.id, asha.email.clone(), LOGIN.to_string());
+        let second = Otp { otp: first.otp.clone(), ..first.clone() };
+        pool.save_otp_with_message(&first, &message).await.unwrap();
+        pool.save_otp_with_message(&second, &message).await.unwrap();
+
+        let latest = pool.get_otp("asha@example.com").await.unwrap();
+        assert_eq!(pool.add_otp_attempt(&latest).await.unwrap(), 1);
+        pool.delete_otp(&latest).await.unwrap();
+        let left = pool.get_otp("asha@example.com").await.unwrap();
+        assert!(left.id < latest.id);
+        assert_eq!(left.attempts, 0);
+    }
+}